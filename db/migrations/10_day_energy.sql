@@ -0,0 +1 @@
+CREATE TABLE day_energy("class_id" TEXT NOT NULL, "date" TEXT NOT NULL, "airconditioner_kwh" REAL NOT NULL, "lighting_kwh" REAL NOT NULL, UNIQUE("class_id", "date"));
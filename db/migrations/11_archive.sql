@@ -0,0 +1,2 @@
+ALTER TABLE school ADD "archived_at" TEXT;
+ALTER TABLE classroom ADD "archived_at" TEXT;
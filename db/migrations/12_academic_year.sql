@@ -0,0 +1,5 @@
+-- Existing classrooms belong to the academic year (starting in April) of the migration
+CREATE TABLE classroom_new("id" TEXT NOT NULL PRIMARY KEY, "school_id" TEXT NOT NULL, "grade" INTEGER NOT NULL, "name" TEXT NOT NULL, "password_hash" TEXT NOT NULL, "archived_at" TEXT, "academic_year" INTEGER NOT NULL, UNIQUE("school_id", "academic_year", "grade", "name"));
+INSERT INTO classroom_new SELECT "id", "school_id", "grade", "name", "password_hash", "archived_at", CAST(strftime('%Y', 'now', 'localtime') AS INTEGER) - (CAST(strftime('%m', 'now', 'localtime') AS INTEGER) < 4) FROM classroom;
+DROP TABLE classroom;
+ALTER TABLE classroom_new RENAME TO classroom;
@@ -0,0 +1 @@
+CREATE TABLE school_calendar("school_id" TEXT NOT NULL, "date" TEXT NOT NULL, "kind" TEXT NOT NULL, "note" TEXT, UNIQUE("school_id", "date"));
@@ -0,0 +1,2 @@
+CREATE TABLE challenge("id" TEXT NOT NULL PRIMARY KEY, "school_id" TEXT NOT NULL, "name" TEXT NOT NULL, "metric" TEXT NOT NULL, "target" REAL NOT NULL, "start_date" TEXT NOT NULL, "end_date" TEXT NOT NULL, "frozen_at" TEXT);
+CREATE TABLE challenge_class("challenge_id" TEXT NOT NULL, "class_id" TEXT NOT NULL, "value" REAL, "achieved" INTEGER, UNIQUE("challenge_id", "class_id"));
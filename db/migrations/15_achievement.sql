@@ -0,0 +1 @@
+CREATE TABLE achievement("class_id" TEXT NOT NULL, "badge" TEXT NOT NULL, "period" TEXT NOT NULL DEFAULT '', "awarded_at" TEXT NOT NULL, UNIQUE("class_id", "badge", "period"));
@@ -0,0 +1 @@
+CREATE TABLE login_attempt("key" TEXT PRIMARY KEY NOT NULL, "failures" INTEGER NOT NULL, "last_failed_at" INTEGER NOT NULL, "locked_until" INTEGER);
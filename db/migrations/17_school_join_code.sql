@@ -0,0 +1,3 @@
+ALTER TABLE school ADD COLUMN "join_code" TEXT;
+-- Codes of the existing schools are generated by the application after the migration
+CREATE UNIQUE INDEX school_join_code ON school("join_code");
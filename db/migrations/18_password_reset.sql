@@ -0,0 +1 @@
+CREATE TABLE password_reset("class_id" TEXT NOT NULL PRIMARY KEY, "code_hash" TEXT NOT NULL, "expires_at" INTEGER NOT NULL, "created_by" TEXT);
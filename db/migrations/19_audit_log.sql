@@ -0,0 +1,2 @@
+CREATE TABLE audit_log("id" TEXT NOT NULL PRIMARY KEY, "time" TEXT NOT NULL, "actor_kind" TEXT NOT NULL, "actor_id" TEXT, "ip" TEXT NOT NULL, "action" TEXT NOT NULL, "target_kind" TEXT NOT NULL, "target_id" TEXT NOT NULL, "before" TEXT, "after" TEXT);
+CREATE INDEX audit_log_target_index ON audit_log("target_id", "time");
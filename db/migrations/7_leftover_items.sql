@@ -0,0 +1,3 @@
+ALTER TABLE day_status ADD "servings" INTEGER;
+CREATE TABLE leftover_item("id" TEXT NOT NULL PRIMARY KEY, "class_id" TEXT NOT NULL, "date" TEXT NOT NULL, "dish" TEXT NOT NULL, "category" TEXT, "amount" REAL NOT NULL, "unit" TEXT NOT NULL, "grams" REAL NOT NULL, "servings" INTEGER, "photo" TEXT);
+CREATE INDEX leftover_item_class_date_index ON leftover_item("class_id", "date");
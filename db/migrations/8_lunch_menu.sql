@@ -0,0 +1 @@
+CREATE TABLE lunch_menu("school_id" TEXT NOT NULL, "date" TEXT NOT NULL, "dish" TEXT NOT NULL, "category" TEXT, UNIQUE("school_id", "date", "dish"));
@@ -0,0 +1,2 @@
+CREATE UNIQUE INDEX teacher_email_unique_index ON teacher("email");
+CREATE TABLE correction_log("id" TEXT NOT NULL PRIMARY KEY, "class_id" TEXT NOT NULL, "date" TEXT NOT NULL, "teacher_id" TEXT NOT NULL, "field" TEXT NOT NULL, "before" INTEGER, "after" INTEGER, "time" TEXT NOT NULL);
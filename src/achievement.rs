@@ -0,0 +1,209 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use chrono::{Datelike, Duration, Local, NaiveDate};
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+
+use crate::{calendar, streak};
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Badge {
+    FirstPoints,     // Got points for the first time
+    ChecklistStreak, // All attendees submitted the checklist for consecutive school days
+    WasteWeek,       // Leftovers under the baseline for a school week
+    SchoolTop,       // Top points of the school in the month
+}
+
+// Days of the streaks which are needed for the badges
+const CHECKLIST_STREAK_DAYS: i64 = 7;
+const WASTE_WEEK_DAYS: i64 = 5;
+
+// Days to look back for the streaks
+const LOOKBACK_DAYS: i64 = 30;
+
+pub const BADGES: [Badge; 4] = [
+    Badge::FirstPoints,
+    Badge::ChecklistStreak,
+    Badge::WasteWeek,
+    Badge::SchoolTop,
+];
+
+// Badges which depend only on the points, for the updates which change nothing else
+pub const POINT_BADGES: [Badge; 2] = [Badge::FirstPoints, Badge::SchoolTop];
+
+impl Badge {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Badge::FirstPoints => "first_points",
+            Badge::ChecklistStreak => "checklist_streak",
+            Badge::WasteWeek => "waste_week",
+            Badge::SchoolTop => "school_top",
+        }
+    }
+
+    pub fn parse(badge: &str) -> Option<Self> {
+        BADGES.into_iter().find(|b| b.as_str() == badge)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Award {
+    pub badge: Badge,
+    pub period: String, // Month of the monthly badges (YYYY-MM), or empty
+    pub awarded_at: String,
+}
+
+pub async fn get_awards(pool: &Pool<Sqlite>, class_id: &str) -> Result<Vec<Award>> {
+    let rows = sqlx::query!(
+        "SELECT badge, period, awarded_at FROM achievement WHERE class_id=$1 ORDER BY awarded_at, rowid",
+        class_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(Award {
+                badge: Badge::parse(&row.badge)?,
+                period: row.period,
+                awarded_at: row.awarded_at,
+            })
+        })
+        .collect())
+}
+
+// The class which got the most points in the school in the month, if any
+async fn school_top_class(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    month: &str,
+) -> Result<Option<String>> {
+    let top = sqlx::query_scalar!(
+        "SELECT classroom.id FROM classroom JOIN day_status ON day_status.class_id=classroom.id
+        WHERE (school_id, academic_year) = (SELECT school_id, academic_year FROM classroom WHERE id=$1)
+            AND archived_at IS NULL AND strftime('%Y-%m', date)=$2
+        GROUP BY classroom.id HAVING SUM(point) > 0 ORDER BY SUM(point) DESC LIMIT 1",
+        class_id,
+        month
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(top)
+}
+
+// Evaluate the rules of the badges and store the new achievements of the class. Returns the new awards.
+pub async fn evaluate(pool: &Pool<Sqlite>, class_id: &str, badges: &[Badge]) -> Result<Vec<Award>> {
+    let today = Local::now().date_naive();
+    let from = today - Duration::days(LOOKBACK_DAYS);
+    let from_str = from.to_string();
+
+    let awarded: HashSet<(String, String)> = sqlx::query!(
+        "SELECT badge, period FROM achievement WHERE class_id=$1",
+        class_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.badge, row.period))
+    .collect();
+
+    let school_id = sqlx::query_scalar!("SELECT school_id FROM classroom WHERE id=$1", class_id)
+        .fetch_one(pool)
+        .await?;
+    let mut school_days: Option<Vec<NaiveDate>> = None;
+
+    let mut new_awards = Vec::new();
+    for &badge in badges {
+        // Monthly badges are given for the last month
+        let period = match badge {
+            Badge::SchoolTop => {
+                let last_month = today.with_day(1).unwrap_or(today) - Duration::days(1);
+                last_month.format("%Y-%m").to_string()
+            }
+            _ => "".to_string(),
+        };
+        if awarded.contains(&(badge.as_str().to_string(), period.clone())) {
+            continue;
+        }
+
+        let achieved = match badge {
+            Badge::FirstPoints => {
+                sqlx::query_scalar!(
+                    "SELECT EXISTS(SELECT * FROM day_status WHERE class_id=$1 AND point > 0)",
+                    class_id
+                )
+                .fetch_one(pool)
+                .await?
+                    > 0
+            }
+            Badge::ChecklistStreak | Badge::WasteWeek => {
+                let school_days = match school_days.as_ref() {
+                    Some(days) => days,
+                    None => school_days
+                        .insert(calendar::school_days(pool, &school_id, from, today).await?),
+                };
+                let (days, needed) = if badge == Badge::ChecklistStreak {
+                    (
+                        streak::full_checklist_days(pool, class_id, &from_str).await?,
+                        CHECKLIST_STREAK_DAYS,
+                    )
+                } else {
+                    (
+                        streak::under_baseline_days(pool, class_id, &from_str).await?,
+                        WASTE_WEEK_DAYS,
+                    )
+                };
+                streak::current_streak(school_days, &days, today) >= needed
+            }
+            Badge::SchoolTop => {
+                school_top_class(pool, class_id, &period).await?.as_deref() == Some(class_id)
+            }
+        };
+        if !achieved {
+            continue;
+        }
+
+        let badge_str = badge.as_str();
+        let awarded_at = sqlx::query_scalar!(
+            r#"INSERT OR IGNORE INTO achievement(class_id, badge, period, awarded_at) VALUES($1, $2, $3, datetime('now', 'localtime'))
+            RETURNING awarded_at AS "awarded_at!""#,
+            class_id,
+            badge_str,
+            period
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        // Other requests may have awarded it concurrently
+        if let Some(awarded_at) = awarded_at {
+            new_awards.push(Award {
+                badge,
+                period,
+                awarded_at,
+            });
+        }
+    }
+    Ok(new_awards)
+}
+
+// New awards of the class. Evaluation failures don't fail the request which has updated the state.
+pub async fn evaluate_or_empty(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    badges: &[Badge],
+) -> Vec<Award> {
+    match evaluate(pool, class_id, badges).await {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!(error = %e, class_id, "failed to evaluate the achievements");
+            Vec::new()
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AwardsResponse {
+    pub achievements: Vec<Award>,
+}
@@ -0,0 +1,195 @@
+use hyper::Request;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use ulid::Ulid;
+
+use crate::utils;
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActorKind {
+    Admin,
+    Teacher,
+    Student,
+    Class,  // Devices signed in to the classroom
+    Device, // Sensors of the classroom
+    Cli,    // Commands run on the server
+    Anonymous,
+}
+
+impl ActorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ActorKind::Admin => "admin",
+            ActorKind::Teacher => "teacher",
+            ActorKind::Student => "student",
+            ActorKind::Class => "class",
+            ActorKind::Device => "device",
+            ActorKind::Cli => "cli",
+            ActorKind::Anonymous => "anonymous",
+        }
+    }
+}
+
+pub struct Actor {
+    pub kind: ActorKind,
+    pub id: Option<String>, // Teacher ID, class ID, or "class_id/student_id" of the student
+    pub ip: String,
+}
+
+impl Actor {
+    // The OS user who runs the command, if known
+    pub fn cli() -> Self {
+        Actor {
+            kind: ActorKind::Cli,
+            id: std::env::var("USER").ok(),
+            ip: "local".to_string(),
+        }
+    }
+
+    // Sensors send the class token, so the handler tells that they are devices
+    pub fn into_device(self) -> Self {
+        match self.kind {
+            ActorKind::Class => Actor {
+                kind: ActorKind::Device,
+                ..self
+            },
+            _ => self,
+        }
+    }
+}
+
+// Who sends the request. Teachers are preferred since they use the devices of the classroom.
+pub async fn get_actor(pool: &Pool<Sqlite>, req: &Request<hyper::body::Incoming>) -> Actor {
+    let ip = utils::get_client_ip(req).to_string();
+    let (kind, id) = if utils::is_admin(req) {
+        (ActorKind::Admin, None)
+    } else if let Ok(teacher) = utils::get_teacher_info_from_token(pool, req).await {
+        (ActorKind::Teacher, Some(teacher.teacher_id))
+    } else if let Ok(student) = utils::get_student_info_from_token(pool, req).await {
+        (
+            ActorKind::Student,
+            Some(format!("{}/{}", student.class_id, student.student_id)),
+        )
+    } else if let Ok(class_id) = utils::get_class_id_from_token(pool, req).await {
+        (ActorKind::Class, Some(class_id))
+    } else {
+        (ActorKind::Anonymous, None)
+    };
+    Actor { kind, id, ip }
+}
+
+// Record the action. Failures don't fail the request which has changed the data.
+pub async fn record(
+    pool: &Pool<Sqlite>,
+    actor: &Actor,
+    action: &str,
+    target_kind: &str,
+    target_id: &str,
+    before: Option<Value>,
+    after: Option<Value>,
+) {
+    let id = Ulid::new().to_string();
+    let kind = actor.kind.as_str();
+    let before = before.map(|v| v.to_string());
+    let after = after.map(|v| v.to_string());
+    let result = sqlx::query!(
+        "INSERT INTO audit_log VALUES($1, datetime('now', 'localtime'), $2, $3, $4, $5, $6, $7, $8, $9)",
+        id,
+        kind,
+        actor.id,
+        actor.ip,
+        action,
+        target_kind,
+        target_id,
+        before,
+        after
+    )
+    .execute(pool)
+    .await;
+    if let Err(e) = result {
+        tracing::error!(error = %e, action, "failed to record the audit log");
+    }
+}
+
+// Record the action once a day per target, for the devices which send the data continuously.
+// The entry of the day keeps the first `before` and takes the latest `after`.
+pub async fn record_daily(
+    pool: &Pool<Sqlite>,
+    actor: &Actor,
+    action: &str,
+    target_kind: &str,
+    target_id: &str,
+    before: Option<Value>,
+    after: Option<Value>,
+) {
+    let after_str = after.as_ref().map(|v| v.to_string());
+    let result = sqlx::query!(
+        "UPDATE audit_log SET after=$1
+        WHERE target_id=$2 AND time >= date('now', 'localtime') AND action=$3 AND target_kind=$4",
+        after_str,
+        target_id,
+        action,
+        target_kind
+    )
+    .execute(pool)
+    .await;
+    match result {
+        Ok(result) if result.rows_affected() > 0 => {}
+        Ok(_) => record(pool, actor, action, target_kind, target_id, before, after).await,
+        Err(e) => tracing::error!(error = %e, action, "failed to record the audit log"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_pool;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn record_daily_puts_changes_of_day_together() {
+        let pool = test_pool().await;
+        let actor = Actor {
+            kind: ActorKind::Device,
+            id: Some("c1".to_string()),
+            ip: "127.0.0.1".to_string(),
+        };
+        for (before, after) in [(None, 5), (Some(5), 8), (Some(8), 12)] {
+            record_daily(
+                &pool,
+                &actor,
+                "classroom.sensor",
+                "classroom",
+                "c1",
+                before.map(|point| json!({ "point": point })),
+                Some(json!({ "point": after })),
+            )
+            .await;
+        }
+        record_daily(
+            &pool,
+            &actor,
+            "classroom.sensor",
+            "classroom",
+            "c2",
+            None,
+            Some(json!({ "point": 1 })),
+        )
+        .await;
+
+        let rows: Vec<(String, Option<String>, Option<String>)> =
+            sqlx::query_as("SELECT target_id, before, after FROM audit_log ORDER BY target_id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            rows,
+            [
+                ("c1".to_string(), None, Some(r#"{"point":12}"#.to_string())),
+                ("c2".to_string(), None, Some(r#"{"point":1}"#.to_string())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Weekday};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use crate::config::CONFIG;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DayKind {
+    SchoolDay,
+    HalfDay,
+    Holiday,
+    Closure,
+}
+
+impl DayKind {
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind.trim().to_lowercase().replace('-', "_").as_str() {
+            "school_day" | "school" => Some(DayKind::SchoolDay),
+            "half_day" | "half" => Some(DayKind::HalfDay),
+            "holiday" => Some(DayKind::Holiday),
+            "closure" | "closed" => Some(DayKind::Closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DayKind::SchoolDay => "school_day",
+            DayKind::HalfDay => "half_day",
+            DayKind::Holiday => "holiday",
+            DayKind::Closure => "closure",
+        }
+    }
+
+    pub fn is_school_day(self) -> bool {
+        matches!(self, DayKind::SchoolDay | DayKind::HalfDay)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CalendarEntry {
+    pub date: String,
+    pub kind: DayKind,
+    pub note: Option<String>,
+}
+
+// Weekdays are school days unless the calendar says otherwise
+fn default_day_kind(date: NaiveDate) -> DayKind {
+    match date.weekday() {
+        Weekday::Sat | Weekday::Sun => DayKind::Holiday,
+        _ => DayKind::SchoolDay,
+    }
+}
+
+pub async fn get_day_kind(
+    pool: &Pool<Sqlite>,
+    school_id: &str,
+    date: NaiveDate,
+) -> Result<DayKind> {
+    let date_str = date.to_string();
+    let kind = sqlx::query_scalar!(
+        "SELECT kind FROM school_calendar WHERE school_id=$1 AND date=$2",
+        school_id,
+        date_str
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(kind
+        .and_then(|kind| DayKind::parse(&kind))
+        .unwrap_or_else(|| default_day_kind(date)))
+}
+
+struct SchoolHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    half_day_end: NaiveTime,
+}
+
+fn parse_school_hours() -> Result<SchoolHours> {
+    let parse = |name: &str, time: &str| {
+        NaiveTime::parse_from_str(time, "%H:%M")
+            .with_context(|| format!("Invalid {} in config: {}", name, time))
+    };
+    let hours = SchoolHours {
+        start: parse("SCHOOL_START_TIME", &CONFIG.school_start_time)?,
+        end: parse("SCHOOL_END_TIME", &CONFIG.school_end_time)?,
+        half_day_end: parse("HALF_DAY_END_TIME", &CONFIG.half_day_end_time)?,
+    };
+    if hours.end <= hours.start || hours.half_day_end <= hours.start {
+        bail!("School hours in config end before they start");
+    }
+    Ok(hours)
+}
+
+static SCHOOL_HOURS: Lazy<SchoolHours> =
+    Lazy::new(|| parse_school_hours().expect("Invalid school hours in config."));
+
+// Check the school hours at the startup, so that the requests don't fail with the bad config
+pub fn check_config() -> Result<()> {
+    parse_school_hours()?;
+    Ok(())
+}
+
+// Whether the classes are held at the time
+pub async fn is_school_time(
+    pool: &Pool<Sqlite>,
+    school_id: &str,
+    time: NaiveDateTime,
+) -> Result<bool> {
+    let hours = &*SCHOOL_HOURS;
+    let end = match get_day_kind(pool, school_id, time.date()).await? {
+        DayKind::SchoolDay => hours.end,
+        DayKind::HalfDay => hours.half_day_end,
+        DayKind::Holiday | DayKind::Closure => return Ok(false),
+    };
+    Ok(hours.start <= time.time() && time.time() < end)
+}
+
+// School days in the period (from and to are inclusive)
+pub async fn school_days(
+    pool: &Pool<Sqlite>,
+    school_id: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<NaiveDate>> {
+    let (from_str, to_str) = (from.to_string(), to.to_string());
+    let entries: HashMap<String, String> = sqlx::query!(
+        "SELECT date, kind FROM school_calendar WHERE school_id=$1 AND date BETWEEN $2 AND $3",
+        school_id,
+        from_str,
+        to_str
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|entry| (entry.date, entry.kind))
+    .collect();
+
+    Ok(from
+        .iter_days()
+        .take_while(|date| *date <= to)
+        .filter(|date| {
+            entries
+                .get(&date.to_string())
+                .and_then(|kind| DayKind::parse(kind))
+                .unwrap_or_else(|| default_day_kind(*date))
+                .is_school_day()
+        })
+        .collect())
+}
+
+// Number of school days in the period (from and to are inclusive)
+pub async fn count_school_days(
+    pool: &Pool<Sqlite>,
+    school_id: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<i64> {
+    Ok(school_days(pool, school_id, from, to).await?.len() as i64)
+}
+
+// CSV columns: date,kind[,note]
+pub fn parse_csv(body: &str) -> Result<Vec<CalendarEntry>> {
+    let mut entries = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (i == 0 && line.starts_with("date")) {
+            continue;
+        }
+        let mut columns = line.splitn(3, ',').map(|column| column.trim());
+        let (Some(date), Some(kind)) = (columns.next(), columns.next()) else {
+            bail!("Invalid calendar line {}", i + 1);
+        };
+        let Some(kind) = DayKind::parse(kind) else {
+            bail!("Invalid kind at line {}", i + 1);
+        };
+        NaiveDate::parse_from_str(date, "%Y-%m-%d")?;
+        entries.push(CalendarEntry {
+            date: date.to_string(),
+            kind,
+            note: columns
+                .next()
+                .filter(|note| !note.is_empty())
+                .map(|note| note.to_string()),
+        });
+    }
+    Ok(entries)
+}
+
+// Events longer than this are rejected, since every day of them is stored
+const MAX_EVENT_DAYS: i64 = 366;
+
+// Local date and time of DATE (20241223), DATE-TIME (20241223T090000) or UTC DATE-TIME (20241222T230000Z).
+// UTC times are converted to the time zone first, so that the event is on the local day. DATE has no time.
+fn parse_ical_value<Tz: TimeZone>(value: &str, tz: &Tz) -> Result<(NaiveDate, Option<NaiveTime>)> {
+    if !value.contains('T') {
+        return Ok((NaiveDate::parse_from_str(value, "%Y%m%d")?, None));
+    }
+    let datetime = match value.strip_suffix('Z') {
+        Some(utc) => tz
+            .from_utc_datetime(&NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")?)
+            .naive_local(),
+        None => NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")?,
+    };
+    Ok((datetime.date(), Some(datetime.time())))
+}
+
+fn parse_ical_date<Tz: TimeZone>(value: &str, tz: &Tz) -> Result<NaiveDate> {
+    Ok(parse_ical_value(value, tz)?.0)
+}
+
+// Last day of the event. DTEND of DATE (VALUE=DATE), or DATE-TIME at midnight, is the exclusive end.
+fn parse_ical_end<Tz: TimeZone>(value: &str, tz: &Tz) -> Result<NaiveDate> {
+    let (date, time) = parse_ical_value(value, tz)?;
+    let exclusive = match time {
+        None => true,
+        Some(time) => time == NaiveTime::MIN,
+    };
+    if exclusive {
+        return Ok(date.pred_opt().unwrap_or(date));
+    }
+    Ok(date)
+}
+
+struct IcalEvent {
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>, // Inclusive
+    summary: Option<String>,
+    kind: DayKind,
+}
+
+// All-day events of iCalendar. The kind is taken from CATEGORIES, or the default kind.
+pub fn parse_ical(body: &str, default_kind: DayKind) -> Result<Vec<CalendarEntry>> {
+    // Unfold the continuation lines
+    let mut lines: Vec<String> = Vec::new();
+    for line in body.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.trim_end().to_string()),
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut event: Option<IcalEvent> = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        // Drop parameters like DTSTART;VALUE=DATE
+        let name = name.split(';').next().unwrap_or(name).to_uppercase();
+        match (name.as_str(), event.as_mut()) {
+            ("BEGIN", None) if value == "VEVENT" => {
+                event = Some(IcalEvent {
+                    start: None,
+                    end: None,
+                    summary: None,
+                    kind: default_kind,
+                });
+            }
+            ("DTSTART", Some(event)) => event.start = Some(parse_ical_date(value, &Local)?),
+            ("DTEND", Some(event)) => event.end = Some(parse_ical_end(value, &Local)?),
+            ("SUMMARY", Some(event)) => event.summary = Some(value.replace("\\,", ",")),
+            ("CATEGORIES", Some(event)) => {
+                if let Some(kind) = value.split(',').find_map(DayKind::parse) {
+                    event.kind = kind;
+                }
+            }
+            ("END", Some(_)) if value == "VEVENT" => {
+                let Some(IcalEvent {
+                    start: Some(start),
+                    end,
+                    summary,
+                    kind,
+                }) = event.take()
+                else {
+                    bail!("VEVENT without DTSTART");
+                };
+                // The exclusive end of one-day events is on the start day
+                let end = end.unwrap_or(start).max(start);
+                if (end - start).num_days() >= MAX_EVENT_DAYS {
+                    bail!("VEVENT longer than {} days", MAX_EVENT_DAYS);
+                }
+                for date in start.iter_days().take_while(|date| *date <= end) {
+                    entries.push(CalendarEntry {
+                        date: date.to_string(),
+                        kind,
+                        note: summary.clone(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    fn dates(body: &str) -> Vec<String> {
+        parse_ical(body, DayKind::Holiday)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.date)
+            .collect()
+    }
+
+    fn event(start: &str, end: &str) -> String {
+        format!(
+            "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\n{}\r\n{}\r\nSUMMARY:x\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+            start, end
+        )
+    }
+
+    #[test]
+    fn all_day_end_is_exclusive() {
+        let body = event("DTSTART;VALUE=DATE:20241223", "DTEND;VALUE=DATE:20241225");
+        assert_eq!(dates(&body), ["2024-12-23", "2024-12-24"]);
+    }
+
+    #[test]
+    fn timed_event_on_one_day() {
+        let body = event("DTSTART:20241223T090000", "DTEND:20241223T120000");
+        assert_eq!(dates(&body), ["2024-12-23"]);
+    }
+
+    #[test]
+    fn timed_event_until_midnight() {
+        let body = event("DTSTART:20241223T090000", "DTEND:20241225T000000");
+        assert_eq!(dates(&body), ["2024-12-23", "2024-12-24"]);
+    }
+
+    #[test]
+    fn utc_time_is_on_local_day() {
+        let jst = FixedOffset::east_opt(9 * 3600).unwrap();
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        // 08:00 on 12-23 in JST
+        assert_eq!(
+            parse_ical_date("20241222T230000Z", &jst).unwrap(),
+            date(2024, 12, 23)
+        );
+        assert_eq!(
+            parse_ical_end("20241222T230000Z", &jst).unwrap(),
+            date(2024, 12, 23)
+        );
+        // Midnight of 12-25 in JST is the exclusive end
+        assert_eq!(
+            parse_ical_end("20241224T150000Z", &jst).unwrap(),
+            date(2024, 12, 24)
+        );
+    }
+
+    #[test]
+    fn long_event_is_rejected() {
+        let body = event("DTSTART;VALUE=DATE:20240101", "DTEND;VALUE=DATE:20260101");
+        assert!(parse_ical(&body, DayKind::Holiday).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::Result;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use crate::utils;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Points,    // Total points
+    Leftovers, // Leftovers per serving (gram)
+    Lighting,  // Estimated saving of the lighting (kWh)
+}
+
+impl Metric {
+    pub fn parse(metric: &str) -> Option<Self> {
+        match metric {
+            "points" => Some(Metric::Points),
+            "leftovers" => Some(Metric::Leftovers),
+            "lighting" => Some(Metric::Lighting),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Metric::Points => "points",
+            Metric::Leftovers => "leftovers",
+            Metric::Lighting => "lighting",
+        }
+    }
+
+    // Leftovers should be under the target, others should reach it
+    fn lower_is_better(self) -> bool {
+        self == Metric::Leftovers
+    }
+
+    fn is_achieved(self, value: f64, target: f64) -> bool {
+        if self.lower_is_better() {
+            value <= target
+        } else {
+            value >= target
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Challenge {
+    pub id: String,
+    pub school_id: String,
+    pub name: String,
+    pub metric: Metric,
+    pub target: f64,
+    pub start_date: String,
+    pub end_date: String,
+    pub frozen_at: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ClassProgress {
+    pub class_id: String,
+    pub grade: i64,
+    pub name: String,
+    pub value: Option<f64>, // None if there is no record yet
+    pub achieved: bool,
+}
+
+pub async fn get_challenge(pool: &Pool<Sqlite>, id: &str) -> Result<Option<Challenge>> {
+    let row = sqlx::query!(
+        "SELECT id, school_id, name, metric, target, start_date, end_date, frozen_at FROM challenge WHERE id=$1",
+        id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.and_then(|row| {
+        Some(Challenge {
+            metric: Metric::parse(&row.metric)?,
+            id: row.id,
+            school_id: row.school_id,
+            name: row.name,
+            target: row.target,
+            start_date: row.start_date,
+            end_date: row.end_date,
+            frozen_at: row.frozen_at,
+        })
+    }))
+}
+
+// Value of the metric of the class in the period (from and to are inclusive)
+async fn calc_value(
+    pool: &Pool<Sqlite>,
+    metric: Metric,
+    class_id: &str,
+    from: &str,
+    to: &str,
+) -> Result<Option<f64>> {
+    let value = match metric {
+        Metric::Points => sqlx::query_scalar!(
+            r#"SELECT CAST(SUM(point) AS REAL) AS "value: f64" FROM day_status WHERE class_id=$1 AND date BETWEEN $2 AND $3"#,
+            class_id,
+            from,
+            to
+        )
+        .fetch_one(pool)
+        .await?,
+        Metric::Leftovers => sqlx::query_scalar!(
+            r#"SELECT CAST(SUM(leftovers) AS REAL) / SUM(COALESCE(servings, attend)) AS "value: f64" FROM day_status
+            WHERE class_id=$1 AND date BETWEEN $2 AND $3 AND leftovers IS NOT NULL AND COALESCE(servings, attend) > 0"#,
+            class_id,
+            from,
+            to
+        )
+        .fetch_one(pool)
+        .await?,
+        Metric::Lighting => sqlx::query_scalar!(
+            r#"SELECT SUM(lighting_kwh) AS "value: f64" FROM day_energy WHERE class_id=$1 AND date BETWEEN $2 AND $3"#,
+            class_id,
+            from,
+            to
+        )
+        .fetch_one(pool)
+        .await?,
+    };
+    Ok(value)
+}
+
+// Progress of the participating classes, ranked from the best.
+// The results are frozen on the first call after the challenge has ended.
+pub async fn get_progress(
+    pool: &Pool<Sqlite>,
+    challenge: &mut Challenge,
+) -> Result<Vec<ClassProgress>> {
+    let mut progress = Vec::new();
+    if challenge.frozen_at.is_some() {
+        progress = sqlx::query_as!(
+            ClassProgress,
+            r#"SELECT class_id, grade, name, value, achieved AS "achieved!: bool" FROM challenge_class
+            JOIN classroom ON classroom.id=challenge_class.class_id WHERE challenge_id=$1"#,
+            challenge.id
+        )
+        .fetch_all(pool)
+        .await?;
+    } else {
+        let classrooms = sqlx::query!(
+            "SELECT class_id, grade, name FROM challenge_class
+            JOIN classroom ON classroom.id=challenge_class.class_id WHERE challenge_id=$1",
+            challenge.id
+        )
+        .fetch_all(pool)
+        .await?;
+        for classroom in classrooms {
+            let value = calc_value(
+                pool,
+                challenge.metric,
+                &classroom.class_id,
+                &challenge.start_date,
+                &challenge.end_date,
+            )
+            .await?;
+            progress.push(ClassProgress {
+                achieved: value.is_some_and(|v| challenge.metric.is_achieved(v, challenge.target)),
+                class_id: classroom.class_id,
+                grade: classroom.grade,
+                name: classroom.name,
+                value,
+            });
+        }
+
+        if utils::today() > challenge.end_date {
+            freeze(pool, challenge, &progress).await?;
+        }
+    }
+
+    let metric = challenge.metric;
+    progress.sort_by(|a, b| match (a.value, b.value) {
+        (Some(a), Some(b)) if metric.lower_is_better() => a.total_cmp(&b),
+        (Some(a), Some(b)) => b.total_cmp(&a),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+    Ok(progress)
+}
+
+async fn freeze(
+    pool: &Pool<Sqlite>,
+    challenge: &mut Challenge,
+    progress: &[ClassProgress],
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    for class in progress {
+        sqlx::query!(
+            "UPDATE challenge_class SET value=$1, achieved=$2 WHERE challenge_id=$3 AND class_id=$4",
+            class.value,
+            class.achieved,
+            challenge.id,
+            class.class_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    let frozen_at = sqlx::query_scalar!(
+        r#"UPDATE challenge SET frozen_at=datetime('now', 'localtime') WHERE id=$1 RETURNING frozen_at AS "frozen_at!""#,
+        challenge.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    challenge.frozen_at = Some(frozen_at);
+    Ok(())
+}
+
+pub fn is_valid_period(start_date: &str, end_date: &str) -> bool {
+    match (
+        NaiveDate::parse_from_str(start_date, "%Y-%m-%d"),
+        NaiveDate::parse_from_str(end_date, "%Y-%m-%d"),
+    ) {
+        (Ok(start), Ok(end)) => start <= end,
+        _ => false,
+    }
+}
@@ -0,0 +1,490 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::NaiveDate;
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use ulid::Ulid;
+
+use crate::{
+    audit::{self, Actor},
+    config::CONFIG,
+    database,
+    export::{self, ExportFormat, ExportRequest},
+    utils::{self, RolloverOptions},
+};
+
+const USAGE: &str = "Usage: ecowatch_backend [COMMAND]
+
+Commands:
+  serve                     Run the server (default)
+  migrate                   Create the database and apply migrations
+  school add <name>
+  school list
+  school rollover <school_id> [--year <n>] [--carry-names] [--promote] [--carry-devices]
+  classroom add --school <id> --grade <n> --name <name> [--password <password>] [--year <n>]
+  classroom list [--school <id>]
+  classroom reset-password <class_id> [--password <password>]
+  classroom delete <class_id>
+  token purge [--class <id> | --all]
+  seed-demo                 Create a demo school with classrooms and history
+  export --school <id> [--from <YYYY-MM-DD>] [--to <YYYY-MM-DD>]
+         [--format csv|ndjson] [--table <table,...>] [--output <file>]";
+
+// Value of `--name value` style option
+fn get_option(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+fn require_option(args: &[String], name: &str) -> Result<String> {
+    get_option(args, name).with_context(|| format!("{} is required\n\n{}", name, USAGE))
+}
+
+// Options without value
+const FLAGS: [&str; 4] = ["--all", "--carry-names", "--promote", "--carry-devices"];
+
+fn has_flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|arg| arg == name)
+}
+
+// Positional argument which is not an option or its value
+fn get_positional(args: &[String], index: usize) -> Option<String> {
+    let mut positionals = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg.starts_with("--") {
+            if !FLAGS.contains(&arg.as_str()) {
+                iter.next();
+            }
+        } else {
+            positionals.push(arg);
+        }
+    }
+    positionals.get(index).map(|arg| arg.to_string())
+}
+
+fn require_positional(args: &[String], index: usize, name: &str) -> Result<String> {
+    get_positional(args, index).with_context(|| format!("<{}> is required\n\n{}", name, USAGE))
+}
+
+async fn connect() -> Result<Pool<Sqlite>> {
+    database::init().await;
+    Ok(database::get_pool().await)
+}
+
+pub async fn run(args: &[String]) -> Result<()> {
+    match args[0].as_str() {
+        "migrate" => migrate().await,
+        "school" => school(&args[1..]).await,
+        "classroom" => classroom(&args[1..]).await,
+        "token" => token(&args[1..]).await,
+        "seed-demo" => seed_demo().await,
+        "export" => export(&args[1..]).await,
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        command => bail!("Unknown command: {}\n\n{}", command, USAGE),
+    }
+}
+
+async fn migrate() -> Result<()> {
+    let pool = connect().await?;
+    let version = sqlx::query_scalar!("SELECT MAX(version) FROM _sqlx_migrations")
+        .fetch_one(&pool)
+        .await?;
+    println!("Database is migrated to version {}", version.unwrap_or(0));
+    Ok(())
+}
+
+async fn school(args: &[String]) -> Result<()> {
+    let pool = connect().await?;
+    match args.first().map(|arg| arg.as_str()) {
+        Some("add") => {
+            let name = require_positional(args, 1, "name")?;
+            let id = Ulid::new().to_string();
+            let join_code = utils::generate_join_code();
+            sqlx::query!(
+                "INSERT INTO school(id, name, join_code) VALUES($1, $2, $3)",
+                id,
+                name,
+                join_code
+            )
+            .execute(&pool)
+            .await?;
+            audit::record(
+                &pool,
+                &Actor::cli(),
+                "school.create",
+                "school",
+                &id,
+                None,
+                Some(json!({ "name": name })),
+            )
+            .await;
+            println!("{}\t{}", id, join_code);
+        }
+        Some("list") => {
+            let schools = sqlx::query!("SELECT id, name, join_code FROM school ORDER BY name")
+                .fetch_all(&pool)
+                .await?;
+            for school in schools {
+                println!(
+                    "{}\t{}\t{}",
+                    school.id,
+                    school.join_code.unwrap_or_default(),
+                    school.name
+                );
+            }
+        }
+        Some("rollover") => {
+            let school_id = require_positional(args, 1, "school_id")?;
+            let year = match get_option(args, "--year") {
+                Some(year) => year.parse()?,
+                None => utils::current_academic_year(),
+            };
+            let options = RolloverOptions {
+                year,
+                carry_names: has_flag(args, "--carry-names"),
+                promote: has_flag(args, "--promote"),
+                carry_devices: has_flag(args, "--carry-devices"),
+            };
+            let result = utils::rollover_school(&pool, &school_id, &options).await?;
+            audit::record(
+                &pool,
+                &Actor::cli(),
+                "school.rollover",
+                "school",
+                &school_id,
+                None,
+                Some(json!({
+                    "year": options.year,
+                    "archived": result.archived,
+                    "created": result.created.iter().map(|c| &c.id).collect::<Vec<_>>(),
+                })),
+            )
+            .await;
+            for classroom in result.created {
+                println!(
+                    "{} -> {}\t{}\t{}",
+                    classroom.from_id, classroom.id, classroom.grade, classroom.name
+                );
+            }
+            println!("Archived {} classrooms", result.archived.len());
+        }
+        _ => bail!("Unknown school command\n\n{}", USAGE),
+    }
+    Ok(())
+}
+
+fn generate_password() -> String {
+    loop {
+        let password: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(10)
+            .map(char::from)
+            .collect();
+        if utils::check_password_strength(&password).is_ok() {
+            return password;
+        }
+    }
+}
+
+// Password of the `--password` option, or the generated one
+fn password_option(args: &[String]) -> Result<String> {
+    match get_option(args, "--password") {
+        Some(password) => {
+            utils::check_password_strength(&password).map_err(|_| {
+                anyhow!(
+                    "The password needs {} or more characters of 2 or more kinds",
+                    CONFIG.password_min_length
+                )
+            })?;
+            Ok(password)
+        }
+        None => Ok(generate_password()),
+    }
+}
+
+async fn classroom(args: &[String]) -> Result<()> {
+    let pool = connect().await?;
+    match args.first().map(|arg| arg.as_str()) {
+        Some("add") => {
+            let school_id = require_option(args, "--school")?;
+            let grade: i64 = require_option(args, "--grade")?.parse()?;
+            let name = require_option(args, "--name")?;
+            let password = password_option(args)?;
+            let academic_year = match get_option(args, "--year") {
+                Some(year) => year.parse()?,
+                None => utils::current_academic_year(),
+            };
+
+            let exists =
+                sqlx::query_scalar!("SELECT EXISTS(SELECT 1 FROM school WHERE id=$1)", school_id)
+                    .fetch_one(&pool)
+                    .await?;
+            if exists <= 0 {
+                bail!("Invalid school_id");
+            }
+
+            let id = Ulid::new().to_string();
+            let hash = utils::compute_password_hash(password.clone());
+            sqlx::query!(
+                "INSERT INTO classroom(id, school_id, grade, name, password_hash, academic_year) VALUES($1, $2, $3, $4, $5, $6)",
+                id,
+                school_id,
+                grade,
+                name,
+                hash,
+                academic_year
+            )
+            .execute(&pool)
+            .await?;
+            audit::record(
+                &pool,
+                &Actor::cli(),
+                "classroom.create",
+                "classroom",
+                &id,
+                None,
+                Some(json!({
+                    "school_id": school_id,
+                    "grade": grade,
+                    "name": name,
+                    "academic_year": academic_year,
+                })),
+            )
+            .await;
+            println!("{}\t{}", id, password);
+        }
+        Some("list") => {
+            let school_id = get_option(args, "--school");
+            let classrooms = sqlx::query!(
+                "SELECT id, school_id, academic_year, grade, name, archived_at FROM classroom
+                WHERE $1 IS NULL OR school_id=$1 ORDER BY school_id, academic_year, grade, name",
+                school_id
+            )
+            .fetch_all(&pool)
+            .await?;
+            for classroom in classrooms {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}{}",
+                    classroom.id,
+                    classroom.school_id,
+                    classroom.academic_year,
+                    classroom.grade,
+                    classroom.name,
+                    if classroom.archived_at.is_some() {
+                        "\t(archived)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        Some("reset-password") => {
+            let class_id = require_positional(args, 1, "class_id")?;
+            let password = password_option(args)?;
+            // Sessions with the old password are signed out
+            if !utils::set_class_password(&pool, &class_id, password.clone()).await? {
+                bail!("Invalid class_id");
+            }
+            audit::record(
+                &pool,
+                &Actor::cli(),
+                "classroom.reset_password",
+                "classroom",
+                &class_id,
+                None,
+                None,
+            )
+            .await;
+            println!("{}", password);
+        }
+        Some("delete") => {
+            let class_id = require_positional(args, 1, "class_id")?;
+            if !utils::delete_classroom(&pool, &class_id).await? {
+                bail!("Invalid class_id");
+            }
+            audit::record(
+                &pool,
+                &Actor::cli(),
+                "classroom.purge",
+                "classroom",
+                &class_id,
+                None,
+                None,
+            )
+            .await;
+        }
+        _ => bail!("Unknown classroom command\n\n{}", USAGE),
+    }
+    Ok(())
+}
+
+async fn token(args: &[String]) -> Result<()> {
+    if args.first().map(|arg| arg.as_str()) != Some("purge") {
+        bail!("Unknown token command\n\n{}", USAGE);
+    }
+    let pool = connect().await?;
+    let mut tx = pool.begin().await?;
+
+    let purged = if has_flag(args, "--all") {
+        sqlx::query!("DELETE FROM class_token")
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            + sqlx::query!("DELETE FROM student_token")
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+            + sqlx::query!("DELETE FROM teacher_token")
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+    } else if let Some(class_id) = get_option(args, "--class") {
+        sqlx::query!("DELETE FROM class_token WHERE class_id=$1", class_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            + sqlx::query!("DELETE FROM student_token WHERE class_id=$1", class_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+            + sqlx::query!(
+                "DELETE FROM teacher_token WHERE teacher_id IN (SELECT id FROM teacher WHERE class_id=$1)",
+                class_id
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+    } else {
+        // Tokens of deleted classrooms and teachers
+        sqlx::query!("DELETE FROM class_token WHERE class_id NOT IN (SELECT id FROM classroom)")
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            + sqlx::query!(
+                "DELETE FROM student_token WHERE class_id NOT IN (SELECT id FROM classroom)"
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            + sqlx::query!(
+                "DELETE FROM teacher_token WHERE teacher_id NOT IN (SELECT id FROM teacher WHERE id IS NOT NULL)"
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+    };
+
+    tx.commit().await?;
+    println!("Purged {} tokens", purged);
+    Ok(())
+}
+
+async fn seed_demo() -> Result<()> {
+    let pool = connect().await?;
+    let mut tx = pool.begin().await?;
+    let mut rng = rand::thread_rng();
+
+    let school_id = Ulid::new().to_string();
+    let join_code = utils::generate_join_code();
+    sqlx::query!(
+        "INSERT INTO school(id, name, join_code) VALUES($1, 'Demo School', $2)",
+        school_id,
+        join_code
+    )
+    .execute(&mut *tx)
+    .await?;
+    println!("school\t{}\t{}", school_id, join_code);
+
+    let password = generate_password();
+    let hash = utils::compute_password_hash(password.clone());
+    let today = NaiveDate::parse_from_str(&utils::today(), "%Y-%m-%d")?;
+    let academic_year = utils::academic_year(today);
+    for grade in 1..=3 {
+        for name in ["1", "2"] {
+            let class_id = Ulid::new().to_string();
+            sqlx::query!(
+                "INSERT INTO classroom(id, school_id, grade, name, password_hash, academic_year) VALUES($1, $2, $3, $4, $5, $6)",
+                class_id,
+                school_id,
+                grade,
+                name,
+                hash,
+                academic_year
+            )
+            .execute(&mut *tx)
+            .await?;
+            println!("classroom\t{}\t{}-{}\t{}", class_id, grade, name, password);
+
+            // History of the last two weeks
+            for days in 0..14 {
+                let date = (today - chrono::Duration::days(days)).to_string();
+                let point: i64 = rng.gen_range(50..300);
+                let attend: i64 = rng.gen_range(25..=30);
+                let leftovers: i64 = rng.gen_range(200..1200);
+                sqlx::query!(
+                    "INSERT INTO day_status(class_id, point, attend, date, leftovers) VALUES($1, $2, $3, $4, $5)",
+                    class_id,
+                    point,
+                    attend,
+                    date,
+                    leftovers
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn export(args: &[String]) -> Result<()> {
+    let school_id = require_option(args, "--school")?;
+    let to = match get_option(args, "--to") {
+        Some(to) => NaiveDate::parse_from_str(&to, "%Y-%m-%d")?,
+        None => NaiveDate::parse_from_str(&utils::today(), "%Y-%m-%d")?,
+    };
+    let from = match get_option(args, "--from") {
+        Some(from) => NaiveDate::parse_from_str(&from, "%Y-%m-%d")?,
+        None => to - chrono::Duration::days(29),
+    };
+    let format = get_option(args, "--format").unwrap_or("ndjson".to_string());
+    let format = ExportFormat::parse(&format).context("Invalid format")?;
+    let tables = match get_option(args, "--table") {
+        Some(tables) => tables.split(',').map(|t| t.to_string()).collect(),
+        None => export::TABLES.iter().map(|t| t.to_string()).collect(),
+    };
+
+    let export_req = ExportRequest {
+        school_id,
+        from: from.to_string(),
+        to: to.to_string(),
+        format,
+        tables,
+    };
+    export_req.validate()?;
+
+    let mut output: Box<dyn AsyncWrite + Unpin> = match get_option(args, "--output") {
+        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+
+    let pool = connect().await?;
+
+    let (tx, mut rx) = mpsc::channel(64);
+    let exporter = tokio::task::spawn(async move { export::export(&pool, &export_req, tx).await });
+    while let Some(chunk) = rx.recv().await {
+        output.write_all(&chunk).await?;
+    }
+    output.flush().await?;
+    exporter.await?
+}
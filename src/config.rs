@@ -7,11 +7,162 @@ pub struct Config {
     pub listen_address: String,
     pub cookie_domain: String,
     pub cookie_cross: bool,
+    #[serde(default)]
+    pub cors_origins: String, // Comma separated origins of the cross-site frontends
     pub database_url: String,
     pub sensor_interval: u64, // msec
+    #[serde(default = "default_portion_grams")]
+    pub portion_grams: f64, // gram per one leftover portion
+    #[serde(default = "default_backdate_days")]
+    pub backdate_days: i64, // days which teachers can correct
+    pub admin_token: Option<String>,
+    pub metrics_token: Option<String>, // Bearer token of the scrapers. ADMIN_TOKEN works too.
+    #[serde(default = "default_academic_year_start_month")]
+    pub academic_year_start_month: u32,
+    #[serde(default = "default_max_grade")]
+    pub max_grade: i64,
+    #[serde(default = "default_school_start_time")]
+    pub school_start_time: String, // HH:MM
+    #[serde(default = "default_school_end_time")]
+    pub school_end_time: String, // HH:MM
+    #[serde(default = "default_half_day_end_time")]
+    pub half_day_end_time: String, // HH:MM
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    #[serde(default)]
+    pub trust_proxy: bool, // Use X-Forwarded-For as the client address
+    #[serde(default = "default_login_max_failures")]
+    pub login_max_failures: i64, // per account
+    #[serde(default = "default_login_max_failures_ip")]
+    pub login_max_failures_ip: i64,
+    #[serde(default = "default_login_lockout_secs")]
+    pub login_lockout_secs: u64,
+    #[serde(default = "default_login_lockout_max_secs")]
+    pub login_lockout_max_secs: u64,
+    #[serde(default = "default_sensor_rate_per_minute")]
+    pub sensor_rate_per_minute: u32, // per classroom
+    #[serde(default = "default_argon2_memory_kib")]
+    pub argon2_memory_kib: u32,
+    #[serde(default = "default_argon2_iterations")]
+    pub argon2_iterations: u32,
+    #[serde(default = "default_argon2_parallelism")]
+    pub argon2_parallelism: u32,
+    #[serde(default = "default_password_min_length")]
+    pub password_min_length: usize,
+    #[serde(default = "default_reset_code_minutes")]
+    pub reset_code_minutes: i64, // lifetime of the password reset codes
+    #[serde(default = "default_shutdown_drain_secs")]
+    pub shutdown_drain_secs: u64, // /readyz fails for this while serving the requests before the shutdown
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64, // Time to wait for the open connections to finish
+    #[serde(default = "default_log_level")]
+    pub log_level: String, // error, warn, info, debug, trace, or the directives like "info,sqlx=warn"
+    #[serde(default)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json, // One JSON object per line for the log collectors
+}
+
+fn default_portion_grams() -> f64 {
+    200.0
+}
+
+fn default_backdate_days() -> i64 {
+    7
+}
+
+fn default_academic_year_start_month() -> u32 {
+    4
+}
+
+fn default_max_grade() -> i64 {
+    6
+}
+
+fn default_school_start_time() -> String {
+    "08:00".to_string()
+}
+
+fn default_school_end_time() -> String {
+    "16:00".to_string()
+}
+
+fn default_half_day_end_time() -> String {
+    "12:00".to_string()
+}
+
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_login_max_failures() -> i64 {
+    5
+}
+
+fn default_login_max_failures_ip() -> i64 {
+    20
+}
+
+fn default_login_lockout_secs() -> u64 {
+    30
+}
+
+fn default_login_lockout_max_secs() -> u64 {
+    3600
+}
+
+fn default_sensor_rate_per_minute() -> u32 {
+    6
+}
+
+fn default_argon2_memory_kib() -> u32 {
+    15000
+}
+
+fn default_argon2_iterations() -> u32 {
+    2
+}
+
+fn default_argon2_parallelism() -> u32 {
+    1
+}
+
+fn default_password_min_length() -> usize {
+    8
+}
+
+fn default_reset_code_minutes() -> i64 {
+    30
+}
+
+fn default_shutdown_drain_secs() -> u64 {
+    10
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
 }
 
 impl Config {
+    // Origins in CORS_ORIGINS without the trailing slashes
+    pub fn cors_origin_list(&self) -> Vec<String> {
+        self.cors_origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect()
+    }
+
     pub fn from_env() -> Result<Self, ConfigError> {
         let cfg = config::Config::builder()
             .add_source(config::Environment::default())
@@ -1,10 +1,16 @@
-use sqlx::{migrate::MigrateDatabase, sqlite::SqlitePool, Sqlite};
+use sqlx::{
+    migrate::{MigrateDatabase, Migrator},
+    sqlite::SqlitePool,
+    Sqlite,
+};
 use tokio::sync::OnceCell;
 
 use crate::config::CONFIG;
 
 static POOL: OnceCell<SqlitePool> = OnceCell::const_new();
 
+pub static MIGRATOR: Migrator = sqlx::migrate!("db/migrations");
+
 pub async fn init() {
     let database_url = &CONFIG.database_url;
 
@@ -21,15 +27,34 @@ pub async fn init() {
         .await
         .expect("Failed connect to database.");
 
-    sqlx::migrate!("db/migrations")
+    MIGRATOR
         .run(&pool)
         .await
-        .inspect_err(|e| eprintln!("{e}"))
+        .inspect_err(|e| tracing::error!(error = %e, "migration failed"))
         .expect("Failed to migrate database.");
 
+    let backfilled = crate::utils::backfill_join_codes(&pool)
+        .await
+        .expect("Failed to backfill join codes.");
+    if backfilled > 0 {
+        tracing::info!(schools = backfilled, "join codes backfilled");
+    }
+
     POOL.set(pool).expect("Failed to set connection pool");
 }
 
 pub async fn get_pool() -> SqlitePool {
     POOL.get().expect("Failed to get connection pool.").clone()
 }
+
+// Migrated in-memory database. Each connection has its own database, so the pool has only one.
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    pool
+}
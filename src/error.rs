@@ -0,0 +1,176 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+
+use crate::utils;
+
+// Errors of the API. The codes are stable so that the clients can localize the messages.
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidParams,               // The request body or the query can't be parsed
+    InvalidField(&'static str),  // The value of the field is invalid
+    AlreadyExists(&'static str), // The resource conflicts with an existing one
+    OutOfCorrectionPeriod,
+    IncorrectPassword,
+    WeakPassword,
+    Unauthorized, // No token
+    InvalidToken,
+    Forbidden,
+    CsrfFailed, // The cookie was sent by a request from an untrusted site
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    TooManyRequests(u64), // Seconds to wait
+    Internal(anyhow::Error),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    error: String, // Message for developers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'static str>,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidParams
+            | ApiError::InvalidField(_)
+            | ApiError::AlreadyExists(_)
+            | ApiError::OutOfCorrectionPeriod
+            | ApiError::IncorrectPassword
+            | ApiError::WeakPassword => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized | ApiError::InvalidToken => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden | ApiError::CsrfFailed => StatusCode::FORBIDDEN,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidParams => "invalid_params",
+            ApiError::InvalidField(_) => "invalid_field",
+            ApiError::AlreadyExists(_) => "already_exists",
+            ApiError::OutOfCorrectionPeriod => "out_of_correction_period",
+            ApiError::IncorrectPassword => "incorrect_password",
+            ApiError::WeakPassword => "weak_password",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::Forbidden => "forbidden",
+            ApiError::CsrfFailed => "csrf_failed",
+            ApiError::NotFound => "not_found",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::PayloadTooLarge => "payload_too_large",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::InvalidParams => "Invalid params".to_string(),
+            ApiError::InvalidField(field) => format!("Invalid {}", field),
+            ApiError::AlreadyExists(field) => format!("The {} already exists", field),
+            ApiError::OutOfCorrectionPeriod => {
+                "The date is out of the correction period".to_string()
+            }
+            ApiError::IncorrectPassword => "Incorrect password".to_string(),
+            ApiError::WeakPassword => format!(
+                "The password needs {} or more characters of 2 or more kinds",
+                crate::config::CONFIG.password_min_length
+            ),
+            ApiError::Unauthorized => "Unauthorized".to_string(),
+            ApiError::InvalidToken => "Invalid token".to_string(),
+            ApiError::Forbidden => "Forbidden".to_string(),
+            ApiError::CsrfFailed => "CSRF check failed".to_string(),
+            ApiError::NotFound => "Not found".to_string(),
+            ApiError::MethodNotAllowed => "Method not allowed".to_string(),
+            ApiError::PayloadTooLarge => "Payload too large".to_string(),
+            ApiError::TooManyRequests(_) => "Too many requests".to_string(),
+            // Don't leak the details of the internal errors
+            ApiError::Internal(_) => "Internal server error".to_string(),
+        }
+    }
+
+    fn field(&self) -> Option<&'static str> {
+        match self {
+            ApiError::InvalidField(field) | ApiError::AlreadyExists(field) => Some(field),
+            _ => None,
+        }
+    }
+
+    // Unique constraint violations are the conflicts with the existing resources
+    pub fn from_insert(e: sqlx::Error, field: &'static str) -> Self {
+        match e.as_database_error() {
+            Some(dbe) if dbe.is_unique_violation() => ApiError::AlreadyExists(field),
+            _ => e.into(),
+        }
+    }
+
+    pub fn into_response(self) -> Response<BoxBody<Bytes, utils::BodyError>> {
+        if let ApiError::Internal(e) = &self {
+            tracing::error!(error = %e, "internal error");
+        }
+        if self.status() == StatusCode::BAD_REQUEST {
+            crate::metrics::record_validation_reject(self.code(), self.field());
+        }
+        let body = ErrorBody {
+            code: self.code(),
+            error: self.message(),
+            field: self.field(),
+        };
+        let json = serde_json::to_string(&body).unwrap_or_default();
+        let mut response = Response::new(utils::full(json));
+        *response.status_mut() = self.status();
+        response.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("application/json"),
+        );
+        if let ApiError::TooManyRequests(secs) = self {
+            response
+                .headers_mut()
+                .insert(hyper::header::RETRY_AFTER, secs.max(1).into());
+        }
+        response
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError::Internal(e)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
+
+// serde errors come from the request bodies, so they are the client errors
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        tracing::debug!(error = %e, "invalid request body");
+        ApiError::InvalidParams
+    }
+}
+
+impl From<hyper::http::Error> for ApiError {
+    fn from(e: hyper::http::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
+
+impl From<hyper::header::InvalidHeaderValue> for ApiError {
+    fn from(e: hyper::header::InvalidHeaderValue) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
@@ -0,0 +1,304 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use futures_util::{Stream, TryStreamExt};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use tokio::sync::mpsc::Sender;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "csv" => Some(ExportFormat::Csv),
+            "ndjson" | "jsonl" => Some(ExportFormat::Ndjson),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+pub const TABLES: [&str; 4] = ["day_status", "checklist", "leftovers", "sensor"];
+
+#[derive(Serialize)]
+struct DayStatusRow {
+    class_id: String,
+    date: String,
+    point: i64,
+    attend: Option<i64>,
+    leftovers: Option<i64>,
+    servings: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ChecklistRow {
+    class_id: String,
+    student_id: String,
+    date: String,
+    list: String,
+}
+
+#[derive(Serialize)]
+struct LeftoversRow {
+    class_id: String,
+    date: String,
+    dish: String,
+    category: Option<String>,
+    amount: f64,
+    unit: String,
+    grams: f64,
+    servings: Option<i64>,
+    photo: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SensorRow {
+    class_id: String,
+    date: String,
+    airconditioner_kwh: f64,
+    lighting_kwh: f64,
+}
+
+pub struct ExportRequest {
+    pub school_id: String,
+    pub from: String,
+    pub to: String,
+    pub format: ExportFormat,
+    pub tables: Vec<String>,
+}
+
+impl ExportRequest {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(table) = self.tables.iter().find(|t| !TABLES.contains(&t.as_str())) {
+            bail!("Unknown table: {}", table);
+        }
+        // CSV has a single header, so one table per file
+        if self.format == ExportFormat::Csv && self.tables.len() != 1 {
+            bail!("CSV export needs exactly one table");
+        }
+        Ok(())
+    }
+}
+
+fn csv_field(value: &Value) -> String {
+    let field = match value {
+        Value::Null => return String::new(),
+        // Spreadsheets run the texts which look like formulas
+        Value::String(s) if s.starts_with(['=', '+', '-', '@', '\t', '\r']) => format!("'{}", s),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+// Records of the CSV with the quoting of RFC 4180, which csv_field writes
+pub fn parse_csv(body: &str) -> Result<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                '"' => quoted = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        bail!("Unterminated quote");
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+async fn send_rows<T, S>(
+    tx: &Sender<Bytes>,
+    table: &str,
+    columns: &[&str],
+    format: ExportFormat,
+    mut rows: S,
+) -> Result<()>
+where
+    T: Serialize,
+    S: Stream<Item = Result<T, sqlx::Error>> + Unpin,
+{
+    if format == ExportFormat::Csv {
+        tx.send(Bytes::from(columns.join(",") + "\n")).await?;
+    }
+
+    while let Some(row) = rows.try_next().await? {
+        let mut value = serde_json::to_value(&row)?;
+        let line = match format {
+            ExportFormat::Csv => {
+                let fields: Vec<String> = columns.iter().map(|c| csv_field(&value[c])).collect();
+                fields.join(",") + "\n"
+            }
+            ExportFormat::Ndjson => {
+                value["table"] = Value::String(table.to_string());
+                value.to_string() + "\n"
+            }
+        };
+        tx.send(Bytes::from(line)).await?;
+    }
+    Ok(())
+}
+
+// Send rows of the school to the channel one by one
+pub async fn export(pool: &Pool<Sqlite>, req: &ExportRequest, tx: Sender<Bytes>) -> Result<()> {
+    let (school_id, from, to) = (&req.school_id, &req.from, &req.to);
+    for table in req.tables.iter() {
+        match table.as_str() {
+            "day_status" => {
+                let rows = sqlx::query_as!(
+                    DayStatusRow,
+                    "SELECT class_id, date, point, attend, leftovers, servings FROM day_status
+                    JOIN classroom ON classroom.id = day_status.class_id
+                    WHERE classroom.school_id=$1 AND date BETWEEN $2 AND $3
+                    ORDER BY date, class_id",
+                    school_id,
+                    from,
+                    to
+                )
+                .fetch(pool);
+                let columns = [
+                    "class_id",
+                    "date",
+                    "point",
+                    "attend",
+                    "leftovers",
+                    "servings",
+                ];
+                send_rows(&tx, table, &columns, req.format, rows).await?;
+            }
+            "checklist" => {
+                let rows = sqlx::query_as!(
+                    ChecklistRow,
+                    "SELECT class_id, student_id, date, list FROM checklist
+                    JOIN classroom ON classroom.id = checklist.class_id
+                    WHERE classroom.school_id=$1 AND date BETWEEN $2 AND $3
+                    ORDER BY date, class_id, student_id",
+                    school_id,
+                    from,
+                    to
+                )
+                .fetch(pool);
+                let columns = ["class_id", "student_id", "date", "list"];
+                send_rows(&tx, table, &columns, req.format, rows).await?;
+            }
+            "leftovers" => {
+                let rows = sqlx::query_as!(
+                    LeftoversRow,
+                    "SELECT class_id, date, dish, category, amount, unit, grams, servings, photo FROM leftover_item
+                    JOIN classroom ON classroom.id = leftover_item.class_id
+                    WHERE classroom.school_id=$1 AND date BETWEEN $2 AND $3
+                    ORDER BY date, class_id",
+                    school_id,
+                    from,
+                    to
+                )
+                .fetch(pool);
+                let columns = [
+                    "class_id", "date", "dish", "category", "amount", "unit", "grams", "servings",
+                    "photo",
+                ];
+                send_rows(&tx, table, &columns, req.format, rows).await?;
+            }
+            "sensor" => {
+                let rows = sqlx::query_as!(
+                    SensorRow,
+                    "SELECT class_id, date, airconditioner_kwh, lighting_kwh FROM day_energy
+                    JOIN classroom ON classroom.id = day_energy.class_id
+                    WHERE classroom.school_id=$1 AND date BETWEEN $2 AND $3
+                    ORDER BY date, class_id",
+                    school_id,
+                    from,
+                    to
+                )
+                .fetch(pool);
+                let columns = ["class_id", "date", "airconditioner_kwh", "lighting_kwh"];
+                send_rows(&tx, table, &columns, req.format, rows).await?;
+            }
+            _ => bail!("Unknown table: {}", table),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn csv_field_quotes_special_characters() {
+        assert_eq!(csv_field(&json!("rice")), "rice");
+        assert_eq!(csv_field(&json!("a,b")), "\"a,b\"");
+        assert_eq!(csv_field(&json!("say \"hi\"")), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field(&Value::Null), "");
+    }
+
+    #[test]
+    fn parse_csv_reads_quoted_fields() {
+        let values = [
+            json!("rice, curry"),
+            json!("say \"hi\""),
+            json!("two\nlines"),
+        ];
+        let line: Vec<String> = values.iter().map(csv_field).collect();
+        let body = format!("a,b,c\r\n{}\n", line.join(","));
+        assert_eq!(
+            parse_csv(&body).unwrap(),
+            [
+                vec!["a", "b", "c"],
+                vec!["rice, curry", "say \"hi\"", "two\nlines"],
+            ]
+        );
+        assert!(parse_csv("\"open,1\n").is_err());
+    }
+
+    #[test]
+    fn csv_field_escapes_formulas() {
+        assert_eq!(csv_field(&json!("=1+1")), "'=1+1");
+        assert_eq!(csv_field(&json!("+81")), "'+81");
+        assert_eq!(csv_field(&json!("-2")), "'-2");
+        assert_eq!(csv_field(&json!("@SUM(A1)")), "'@SUM(A1)");
+        assert_eq!(
+            csv_field(&json!("=HYPERLINK(\"x\",\"y\")")),
+            "\"'=HYPERLINK(\"\"x\"\",\"\"y\"\")\""
+        );
+        // Numbers are not texts
+        assert_eq!(csv_field(&json!(-2)), "-2");
+    }
+}
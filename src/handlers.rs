@@ -1,46 +1,237 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
-use hyper::{Method, Request, Response, StatusCode};
+use hyper::{Method, Request, Response};
+use once_cell::sync::Lazy;
 
-use crate::utils;
+use crate::{
+    config::CONFIG,
+    error::ApiError,
+    middleware::{BodyLimit, Cors, Csrf, Logger, RateLimit, RequestMetrics, RequireAdmin},
+    router::{Middleware, Router},
+    utils,
+};
 
+mod admin;
+mod auth;
+mod challenge;
 mod classroom;
+mod monitoring;
 mod school;
 mod student;
+mod teacher;
 
-pub async fn route(
-    req: Request<hyper::body::Incoming>,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
-    if req.method() == &Method::OPTIONS {
-        return utils::response_empty(StatusCode::OK);
-    }
+static ROUTER: Lazy<Router> = Lazy::new(|| {
+    let admin: Arc<dyn Middleware> = Arc::new(RequireAdmin);
 
-    match (req.method(), req.uri().path()) {
-        (&Method::POST, "/classroom/create") => classroom::handler_create(req).await,
-        (&Method::GET, "/classroom/get_all") => classroom::handler_get_all(req).await,
-        (&Method::POST, "/classroom/login") => classroom::handler_login(req).await,
-        (&Method::POST, "/classroom/logout") => classroom::handler_logout(req).await,
-        (&Method::GET, "/classroom/get_now_status") => classroom::handler_get_now_status(req).await,
-        (&Method::GET, "/classroom/get_status_history") => {
-            classroom::handler_day_status_history(req).await
-        }
-        (&Method::GET, "/classroom/point") => classroom::handler_point(req).await,
-        (&Method::POST, "/classroom/regist_attendance") => {
-            classroom::handler_regist_attendance(req).await
-        }
-        (&Method::POST, "/classroom/regist_leftovers") => {
-            classroom::handler_regist_leftovers(req).await
-        }
-        (&Method::POST, "/classroom/sensor") => classroom::handler_sensor(req).await,
-        (&Method::POST, "/classroom/set_point") => classroom::handler_setpoint(req).await, // For demo
-        (&Method::POST, "/school/create") => school::handler_create(req).await,
-        (&Method::POST, "/student/login") => student::handler_login(req).await,
-        (&Method::GET, "/student/exist_checklist") => student::handler_exist_checklist(req).await,
-        (&Method::POST, "/student/checklist") => student::handler_checklist(req).await,
-        (&Method::GET, "/student/point") => student::handler_point(req).await,
+    Router::new()
+        .layer(Logger)
+        .layer(RequestMetrics)
+        .layer(Cors::from_config())
+        .layer(Csrf::from_config(vec![
+            "/classroom/login",
+            "/classroom/token",
+            "/classroom/reset_password",
+            "/classroom/reset_password/token",
+            "/student/login",
+            "/student/token",
+            "/teacher/login",
+            "/teacher/token",
+        ]))
+        .layer(BodyLimit)
+        .route(Method::POST, "/classroom/create", classroom::handler_create)
+        .route(Method::POST, "/classroom/login", classroom::handler_login)
+        .route(
+            Method::POST,
+            "/classroom/token",
+            classroom::handler_login_token,
+        )
+        .route(Method::POST, "/classroom/logout", classroom::handler_logout)
+        .route(
+            Method::PATCH,
+            "/classroom/update",
+            classroom::handler_update,
+        )
+        .route(
+            Method::POST,
+            "/classroom/password",
+            classroom::handler_change_password,
+        )
+        .route(
+            Method::POST,
+            "/classroom/reset_code",
+            classroom::handler_reset_code,
+        )
+        .route(
+            Method::POST,
+            "/classroom/reset_password",
+            classroom::handler_reset_password,
+        )
+        .route(
+            Method::POST,
+            "/classroom/reset_password/token",
+            classroom::handler_reset_password_token,
+        )
+        .route_with(
+            Method::DELETE,
+            "/classroom/delete",
+            vec![admin.clone()],
+            classroom::handler_delete,
+        )
+        .route(
+            Method::GET,
+            "/classroom/get_now_status",
+            classroom::handler_get_now_status,
+        )
+        .route(
+            Method::GET,
+            "/classroom/get_status_history",
+            classroom::handler_day_status_history,
+        )
+        .route(
+            Method::GET,
+            "/classroom/{id}/history",
+            classroom::handler_history,
+        )
+        .route(Method::GET, "/classroom/point", classroom::handler_point)
+        .route(
+            Method::POST,
+            "/classroom/regist_attendance",
+            classroom::handler_regist_attendance,
+        )
+        .route(
+            Method::POST,
+            "/classroom/regist_leftovers",
+            classroom::handler_regist_leftovers,
+        )
+        .route(
+            Method::GET,
+            "/classroom/leftovers",
+            classroom::handler_get_leftovers,
+        )
+        .route(Method::GET, "/classroom/impact", classroom::handler_impact)
+        .route(
+            Method::GET,
+            "/classroom/summary",
+            classroom::handler_summary,
+        )
+        .route(
+            Method::GET,
+            "/classroom/achievements",
+            classroom::handler_achievements,
+        )
+        .route_with(
+            Method::POST,
+            "/classroom/sensor",
+            vec![Arc::new(RateLimit::per_minute(
+                CONFIG.sensor_rate_per_minute,
+            ))],
+            classroom::handler_sensor,
+        )
+        .route(
+            Method::POST,
+            "/classroom/set_point",
+            classroom::handler_setpoint,
+        ) // For demo
+        .route(Method::POST, "/school/create", school::handler_create)
+        .route_with(
+            Method::PATCH,
+            "/school/update",
+            vec![admin.clone()],
+            school::handler_update,
+        )
+        .route_with(
+            Method::DELETE,
+            "/school/delete",
+            vec![admin.clone()],
+            school::handler_delete,
+        )
+        .route_with(
+            Method::POST,
+            "/school/rollover",
+            vec![admin.clone()],
+            school::handler_rollover,
+        )
+        .route(
+            Method::GET,
+            "/school/{code}/classrooms",
+            school::handler_classrooms,
+        )
+        .route(Method::POST, "/school/menu", school::handler_import_menu)
+        .route(Method::GET, "/school/menu", school::handler_get_menu)
+        .route(
+            Method::POST,
+            "/school/calendar",
+            school::handler_import_calendar,
+        )
+        .route(
+            Method::GET,
+            "/school/calendar",
+            school::handler_get_calendar,
+        )
+        .route(Method::GET, "/school/impact", school::handler_impact)
+        .route(Method::GET, "/school/export", school::handler_export)
+        .route(Method::POST, "/student/login", student::handler_login)
+        .route(Method::POST, "/student/token", student::handler_login_token)
+        .route(
+            Method::GET,
+            "/student/exist_checklist",
+            student::handler_exist_checklist,
+        )
+        .route(
+            Method::POST,
+            "/student/checklist",
+            student::handler_checklist,
+        )
+        .route(Method::GET, "/student/point", student::handler_point)
+        .route(Method::POST, "/teacher/create", teacher::handler_create)
+        .route(Method::POST, "/teacher/login", teacher::handler_login)
+        .route(Method::POST, "/teacher/token", teacher::handler_login_token)
+        .route(Method::POST, "/challenge/create", challenge::handler_create)
+        .route(Method::GET, "/challenge/list", challenge::handler_list)
+        .route(
+            Method::GET,
+            "/challenge/progress",
+            challenge::handler_progress,
+        )
+        .route(Method::GET, "/auth/csrf", auth::handler_csrf)
+        .route_with(
+            Method::GET,
+            "/admin/schools",
+            vec![admin.clone()],
+            admin::handler_schools,
+        )
+        .route_with(
+            Method::POST,
+            "/admin/schools/{id}/join_code",
+            vec![admin.clone()],
+            admin::handler_regenerate_join_code,
+        )
+        .route_with(
+            Method::GET,
+            "/admin/classrooms",
+            vec![admin.clone()],
+            admin::handler_classrooms,
+        )
+        .route_with(
+            Method::GET,
+            "/admin/audit",
+            vec![admin],
+            admin::handler_audit,
+        )
+        .route(Method::GET, "/metrics", monitoring::handler_metrics)
+        .route(Method::GET, "/healthz", monitoring::handler_healthz)
+        .route(Method::GET, "/readyz", monitoring::handler_readyz)
+});
 
-        // Return the 404 Not Found for other routes.
-        _ => utils::response_empty(StatusCode::NOT_FOUND),
-    }
+pub async fn route(
+    req: Request<hyper::body::Incoming>,
+) -> Result<Response<BoxBody<Bytes, utils::BodyError>>> {
+    // Errors are sent to the clients in the common JSON format
+    Ok(ROUTER
+        .handle(req)
+        .await
+        .unwrap_or_else(ApiError::into_response))
 }
@@ -0,0 +1,261 @@
+use chrono::NaiveDate;
+use hyper::{Request, StatusCode};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{audit, database, error::ApiError, utils};
+
+#[derive(Serialize)]
+struct Page<T> {
+    total: i64,
+    page: i64,
+    per_page: i64,
+    items: Vec<T>,
+}
+
+#[derive(Serialize)]
+struct AdminSchool {
+    id: String,
+    name: String,
+    join_code: Option<String>,
+    archived_at: Option<String>,
+}
+
+// Schools whose name or join code matches `q`
+pub async fn handler_schools(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let (page, per_page) = utils::get_query_page(&req)?;
+    let q = utils::get_query_param(&req, "q").filter(|q| !q.is_empty());
+    let offset = (page - 1).saturating_mul(per_page);
+
+    let pool = &database::get_pool().await;
+
+    let total = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM school WHERE $1 IS NULL OR instr(lower(name), lower($1)) > 0 OR join_code=upper($1)",
+        q
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let items = sqlx::query_as!(
+        AdminSchool,
+        "SELECT id, name, join_code, archived_at FROM school
+        WHERE $1 IS NULL OR instr(lower(name), lower($1)) > 0 OR join_code=upper($1)
+        ORDER BY name, id LIMIT $2 OFFSET $3",
+        q,
+        per_page,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+
+    utils::response_struct_json(
+        StatusCode::OK,
+        &Page {
+            total,
+            page,
+            per_page,
+            items,
+        },
+    )
+}
+
+#[derive(Serialize)]
+struct AdminClassroom {
+    id: String,
+    school_id: String,
+    school_name: String,
+    academic_year: i64,
+    grade: i64,
+    name: String,
+    archived_at: Option<String>,
+}
+
+// Classrooms whose name or school name matches `q`, optionally in the school of `school_id`
+pub async fn handler_classrooms(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let (page, per_page) = utils::get_query_page(&req)?;
+    let q = utils::get_query_param(&req, "q").filter(|q| !q.is_empty());
+    let school_id = utils::get_query_param(&req, "school_id");
+    let offset = (page - 1).saturating_mul(per_page);
+
+    let pool = &database::get_pool().await;
+
+    let total = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM classroom JOIN school ON school.id=classroom.school_id
+        WHERE ($1 IS NULL OR instr(lower(classroom.name), lower($1)) > 0 OR instr(lower(school.name), lower($1)) > 0)
+            AND ($2 IS NULL OR classroom.school_id=$2)",
+        q,
+        school_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let items = sqlx::query_as!(
+        AdminClassroom,
+        "SELECT classroom.id, classroom.school_id, school.name AS school_name, classroom.academic_year,
+            classroom.grade, classroom.name, classroom.archived_at
+        FROM classroom JOIN school ON school.id=classroom.school_id
+        WHERE ($1 IS NULL OR instr(lower(classroom.name), lower($1)) > 0 OR instr(lower(school.name), lower($1)) > 0)
+            AND ($2 IS NULL OR classroom.school_id=$2)
+        ORDER BY school.name, classroom.academic_year DESC, classroom.grade, classroom.name LIMIT $3 OFFSET $4",
+        q,
+        school_id,
+        per_page,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+
+    utils::response_struct_json(
+        StatusCode::OK,
+        &Page {
+            total,
+            page,
+            per_page,
+            items,
+        },
+    )
+}
+
+#[derive(Serialize)]
+struct JoinCodeResponse {
+    join_code: String,
+}
+
+// Issue a new join code of the school. The old code stops working.
+pub async fn handler_regenerate_join_code(
+    req: Request<hyper::body::Incoming>,
+) -> utils::HandlerResponse {
+    let school_id = utils::get_path_param(&req, "id").ok_or(ApiError::InvalidParams)?;
+
+    let pool = &database::get_pool().await;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let join_code = utils::generate_join_code();
+    let result = sqlx::query!(
+        "UPDATE school SET join_code=$1 WHERE id=$2",
+        join_code,
+        school_id
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| ApiError::from_insert(e, "join_code"))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    audit::record(
+        pool,
+        &actor,
+        "school.join_code",
+        "school",
+        &school_id,
+        None,
+        None,
+    )
+    .await;
+
+    utils::response_struct_json(StatusCode::OK, &JoinCodeResponse { join_code })
+}
+
+#[derive(Serialize)]
+struct AuditEntry {
+    id: String,
+    time: String,
+    actor_kind: String,
+    actor_id: Option<String>,
+    ip: String,
+    action: String,
+    target_kind: String,
+    target_id: String,
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+// Audit log, newest first. Filters by `actor_kind`, `actor_id`, `action`, `target_kind`, `target_id`
+// and the dates `from` and `to` (inclusive).
+pub async fn handler_audit(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let (page, per_page) = utils::get_query_page(&req)?;
+    let offset = (page - 1).saturating_mul(per_page);
+    let param = |key| utils::get_query_param(&req, key).filter(|v| !v.is_empty());
+    let actor_kind = param("actor_kind");
+    let actor_id = param("actor_id");
+    let action = param("action");
+    let target_kind = param("target_kind");
+    let target_id = param("target_id");
+    let from = param("from");
+    let to = param("to");
+    for (field, date) in [("from", &from), ("to", &to)] {
+        if date
+            .as_deref()
+            .is_some_and(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").is_err())
+        {
+            return Err(ApiError::InvalidField(field));
+        }
+    }
+
+    let pool = &database::get_pool().await;
+
+    let total = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM audit_log
+        WHERE ($1 IS NULL OR actor_kind=$1) AND ($2 IS NULL OR actor_id=$2) AND ($3 IS NULL OR action=$3)
+            AND ($4 IS NULL OR target_kind=$4) AND ($5 IS NULL OR target_id=$5)
+            AND ($6 IS NULL OR date(time)>=$6) AND ($7 IS NULL OR date(time)<=$7)",
+        actor_kind,
+        actor_id,
+        action,
+        target_kind,
+        target_id,
+        from,
+        to
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let rows = sqlx::query!(
+        "SELECT * FROM audit_log
+        WHERE ($1 IS NULL OR actor_kind=$1) AND ($2 IS NULL OR actor_id=$2) AND ($3 IS NULL OR action=$3)
+            AND ($4 IS NULL OR target_kind=$4) AND ($5 IS NULL OR target_id=$5)
+            AND ($6 IS NULL OR date(time)>=$6) AND ($7 IS NULL OR date(time)<=$7)
+        ORDER BY time DESC, id DESC LIMIT $8 OFFSET $9",
+        actor_kind,
+        actor_id,
+        action,
+        target_kind,
+        target_id,
+        from,
+        to,
+        per_page,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let parse = |v: Option<String>| v.and_then(|v| serde_json::from_str(&v).ok());
+    let items = rows
+        .into_iter()
+        .map(|row| AuditEntry {
+            id: row.id,
+            time: row.time,
+            actor_kind: row.actor_kind,
+            actor_id: row.actor_id,
+            ip: row.ip,
+            action: row.action,
+            target_kind: row.target_kind,
+            target_id: row.target_id,
+            before: parse(row.before),
+            after: parse(row.after),
+        })
+        .collect();
+
+    utils::response_struct_json(
+        StatusCode::OK,
+        &Page {
+            total,
+            page,
+            per_page,
+            items,
+        },
+    )
+}
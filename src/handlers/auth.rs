@@ -0,0 +1,23 @@
+use hyper::{
+    header::{HeaderValue, SET_COOKIE},
+    Request, StatusCode,
+};
+use ulid::Ulid;
+
+use crate::utils::{self, CsrfResponse};
+
+// CSRF token of the browser for the frontends which lost it by reloading.
+// Other sites can't read it because of CORS.
+pub async fn handler_csrf(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    if let Some(csrf_token) = utils::get_cookie(&req, utils::CSRF_TOKEN.to_string()) {
+        return utils::response_struct_json(StatusCode::OK, &CsrfResponse { csrf_token });
+    }
+
+    let csrf_token = Ulid::new().to_string();
+    let csrf_cookie = utils::create_cookie(utils::CSRF_TOKEN.to_string(), csrf_token.clone());
+    let mut response = utils::response_struct_json(StatusCode::OK, &CsrfResponse { csrf_token })?;
+    response
+        .headers_mut()
+        .append(SET_COOKIE, HeaderValue::from_str(csrf_cookie.as_str())?);
+    Ok(response)
+}
@@ -0,0 +1,202 @@
+use hyper::{Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use ulid::Ulid;
+
+use crate::{
+    audit,
+    challenge::{self, Challenge, ClassProgress, Metric},
+    database,
+    error::ApiError,
+    utils,
+};
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    name: String,
+    metric: Metric,
+    target: f64,
+    start_date: String,
+    end_date: String,
+    class_ids: Option<Vec<String>>, // All classes of the academic year if omitted
+}
+
+#[derive(Serialize)]
+struct CreateResponse {
+    id: String,
+}
+
+// Create a challenge in the school of the logged-in teacher
+pub async fn handler_create(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let teacher = utils::get_teacher_info_from_token(pool, &req).await?;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let req_data = utils::parse_req_json::<CreateRequest>(req).await?;
+
+    if req_data.name.is_empty()
+        || !req_data.target.is_finite()
+        || !challenge::is_valid_period(&req_data.start_date, &req_data.end_date)
+    {
+        return Err(ApiError::InvalidParams);
+    }
+
+    let classroom = sqlx::query!(
+        "SELECT school_id, academic_year FROM classroom WHERE id=$1",
+        teacher.class_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let school_class_ids = sqlx::query_scalar!(
+        "SELECT id FROM classroom WHERE school_id=$1 AND academic_year=$2 AND archived_at IS NULL",
+        classroom.school_id,
+        classroom.academic_year
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let class_ids = match req_data.class_ids {
+        Some(class_ids) => {
+            if class_ids.is_empty() || class_ids.iter().any(|id| !school_class_ids.contains(id)) {
+                return Err(ApiError::InvalidField("class_ids"));
+            }
+            class_ids
+        }
+        None => school_class_ids,
+    };
+
+    let mut tx = pool.begin().await?;
+
+    let id = Ulid::new().to_string();
+    let metric = req_data.metric.as_str();
+    sqlx::query!(
+        "INSERT INTO challenge(id, school_id, name, metric, target, start_date, end_date) VALUES($1, $2, $3, $4, $5, $6, $7)",
+        id,
+        classroom.school_id,
+        req_data.name,
+        metric,
+        req_data.target,
+        req_data.start_date,
+        req_data.end_date
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    for class_id in &class_ids {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO challenge_class(challenge_id, class_id) VALUES($1, $2)",
+            id,
+            class_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    audit::record(
+        pool,
+        &actor,
+        "challenge.create",
+        "challenge",
+        &id,
+        None,
+        Some(json!({
+            "name": req_data.name,
+            "metric": metric,
+            "target": req_data.target,
+            "start_date": req_data.start_date,
+            "end_date": req_data.end_date,
+            "class_ids": class_ids,
+        })),
+    )
+    .await;
+
+    utils::response_struct_json(StatusCode::CREATED, &CreateResponse { id })
+}
+
+#[derive(Serialize)]
+struct ChallengeItem {
+    #[serde(flatten)]
+    challenge: Challenge,
+    progress: Option<ClassProgress>, // Progress of the logged-in class
+    rank: i64,
+    class_num: i64,
+}
+
+// Challenges which the logged-in class participates in
+pub async fn handler_list(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
+
+    let challenge_ids = sqlx::query_scalar!(
+        "SELECT challenge_id FROM challenge_class JOIN challenge ON challenge.id=challenge_class.challenge_id
+        WHERE class_id=$1 ORDER BY start_date DESC",
+        class_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut list = Vec::new();
+    for challenge_id in challenge_ids {
+        let mut challenge = match challenge::get_challenge(pool, &challenge_id).await? {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let progress = challenge::get_progress(pool, &mut challenge).await?;
+
+        let class_num = progress.len() as i64;
+        let position = progress.iter().position(|p| p.class_id == class_id);
+        list.push(ChallengeItem {
+            challenge,
+            rank: position.map_or(class_num, |i| i as i64 + 1),
+            class_num,
+            progress: position.and_then(|i| progress.into_iter().nth(i)),
+        });
+    }
+
+    utils::response_struct_json(StatusCode::OK, &list)
+}
+
+#[derive(Serialize)]
+struct ProgressResponse {
+    #[serde(flatten)]
+    challenge: Challenge,
+    progress: Vec<ClassProgress>,
+}
+
+// Progress of all participating classes, ranked from the best
+pub async fn handler_progress(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
+
+    let id = utils::get_query_param(&req, "id").ok_or(ApiError::InvalidParams)?;
+
+    let mut challenge = challenge::get_challenge(pool, &id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    // Challenges are visible only in the school
+    let school_id = sqlx::query_scalar!("SELECT school_id FROM classroom WHERE id=$1", class_id)
+        .fetch_one(pool)
+        .await?;
+    if school_id != challenge.school_id {
+        return Err(ApiError::NotFound);
+    }
+
+    let progress = challenge::get_progress(pool, &mut challenge).await?;
+
+    utils::response_struct_json(
+        StatusCode::OK,
+        &ProgressResponse {
+            challenge,
+            progress,
+        },
+    )
+}
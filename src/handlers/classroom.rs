@@ -1,15 +1,23 @@
-use chrono::Utc;
+use anyhow::Result;
+use chrono::{Local, NaiveDate, Utc};
 use hyper::{
-    header::{HeaderName, HeaderValue, SET_COOKIE},
+    header::{HeaderName, HeaderValue},
     Request, StatusCode,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sqlx::{Pool, Sqlite};
 use ulid::Ulid;
 
 use crate::{
+    achievement::{self, Award, AwardsResponse},
+    audit, calendar,
+    config::CONFIG,
     database,
-    utils::{self, calc_leftovers_point, DayStatus, Sensor},
+    error::{ApiError, ApiResult},
+    lockout, metrics,
+    streak::{self, ClassStreaks},
+    utils::{self, calc_leftovers_point, DayStatus, LeftoverUnit, Sensor},
 };
 
 #[derive(Deserialize)]
@@ -18,73 +26,63 @@ struct CreateRequest {
     grade: i64,
     name: String,
     password: String,
+    academic_year: Option<i64>,
 }
 
 pub async fn handler_create(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
-    let create_data = {
-        let result = utils::parse_req_json::<CreateRequest>(req).await;
-        match result {
-            Ok(r) => r,
-            Err(e) => {
-                println!("{}", e.to_string());
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid params".to_string(),
-                );
-            }
-        }
-    };
+    let pool = &database::get_pool().await;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let create_data = utils::parse_req_json::<CreateRequest>(req).await?;
+    utils::check_password_strength(&create_data.password)?;
 
     let hash = utils::compute_password_hash(create_data.password);
     let id = Ulid::new().to_string();
+    let academic_year = create_data
+        .academic_year
+        .unwrap_or_else(utils::current_academic_year);
 
-    let pool = &database::get_pool().await;
-
-    let result = sqlx::query_scalar!(
-        "SELECT EXISTS(SELECT 1 FROM school WHERE id=$1)",
+    let count = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM school WHERE id=$1 AND archived_at IS NULL)",
         create_data.school_id
     )
     .fetch_one(pool)
-    .await;
+    .await?;
 
-    match result {
-        Ok(count) => {
-            if count <= 0 {
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid school_id".to_string(),
-                );
-            }
-        }
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+    if count <= 0 {
+        return Err(ApiError::InvalidField("school_id"));
     }
 
-    let result = sqlx::query!(
-        "INSERT INTO classroom VALUES($1, $2, $3, $4, $5)",
+    sqlx::query!(
+        "INSERT INTO classroom(id, school_id, grade, name, password_hash, academic_year) VALUES($1, $2, $3, $4, $5, $6)",
         id,
         create_data.school_id,
         create_data.grade,
         create_data.name,
-        hash
+        hash,
+        academic_year
     )
     .execute(pool)
+    .await
+    .map_err(|e| ApiError::from_insert(e, "classroom"))?;
+
+    audit::record(
+        pool,
+        &actor,
+        "classroom.create",
+        "classroom",
+        &id,
+        None,
+        Some(json!({
+            "school_id": create_data.school_id,
+            "grade": create_data.grade,
+            "name": create_data.name,
+            "academic_year": academic_year,
+        })),
+    )
     .await;
 
-    if let Err(e) = result {
-        if let Some(dbe) = e.as_database_error() {
-            println!("{}", dbe.message());
-            return utils::response_error_message(
-                StatusCode::BAD_REQUEST,
-                "This classroom is already exist".to_string(),
-            );
-        }
-        println!("{}", e.to_string());
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
     utils::response_empty(StatusCode::OK)
 }
 
@@ -95,86 +93,371 @@ struct LoginRequest {
 }
 
 pub async fn handler_login(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
-    let login_data = {
-        let result = utils::parse_req_json::<LoginRequest>(req).await;
-        match result {
-            Ok(r) => r,
-            Err(e) => {
-                println!("{}", e.to_string());
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid params".to_string(),
-                );
-            }
-        }
-    };
+    login(req, false).await
+}
+
+// Login which returns the token in the body to send it as the bearer token
+pub async fn handler_login_token(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    login(req, true).await
+}
+
+async fn login(req: Request<hyper::body::Incoming>, in_body: bool) -> utils::HandlerResponse {
+    let ip_key = lockout::ip_key(utils::get_client_ip(&req));
+
+    let login_data = utils::parse_req_json::<LoginRequest>(req).await?;
 
     let pool = &database::get_pool().await;
 
-    let result = sqlx::query_scalar!(
-        "SELECT password_hash FROM classroom WHERE id=$1",
+    let class_key = lockout::account_key("class", &login_data.class_id);
+    lockout::check(pool, &[ip_key.clone(), class_key.clone()]).await?;
+
+    let Some(hashed_password) = sqlx::query_scalar!(
+        "SELECT password_hash FROM classroom WHERE id=$1 AND archived_at IS NULL",
         login_data.class_id
     )
     .fetch_optional(pool)
+    .await?
+    else {
+        metrics::record_login_failure("class");
+        lockout::record_failure(pool, &[ip_key]).await?;
+        return Err(ApiError::InvalidField("class_id"));
+    };
+
+    // Check password
+    if !utils::verify_password(login_data.password.clone(), hashed_password.clone())? {
+        metrics::record_login_failure("class");
+        lockout::record_failure(pool, &[ip_key, class_key]).await?;
+        return Err(ApiError::IncorrectPassword);
+    }
+    lockout::reset(pool, &class_key).await?;
+
+    // Upgrade the hash made with the older params while we know the password
+    if utils::needs_rehash(&hashed_password) {
+        let hash = utils::compute_password_hash(login_data.password);
+        sqlx::query!(
+            "UPDATE classroom SET password_hash=$1 WHERE id=$2 AND password_hash=$3",
+            hash,
+            login_data.class_id,
+            hashed_password
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    issue_class_token(pool, &login_data.class_id, in_body).await
+}
+
+// Sign in the device to the classroom with a new token
+async fn issue_class_token(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    in_body: bool,
+) -> utils::HandlerResponse {
+    let token = Ulid::new().to_string();
+    sqlx::query!("INSERT INTO class_token VALUES($1, $2)", token, class_id)
+        .execute(pool)
+        .await?;
+
+    utils::response_token(utils::CLASS_TOKEN, token, in_body)
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordRequest {
+    old_password: String,
+    new_password: String,
+}
+
+// Change the password of the logged-in classroom. Other devices are signed out.
+pub async fn handler_change_password(
+    req: Request<hyper::body::Incoming>,
+) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
+    let ip_key = lockout::ip_key(utils::get_client_ip(&req));
+    let in_body = utils::get_bearer_token(&req).is_some();
+    let actor = audit::get_actor(pool, &req).await;
+
+    let req_data = utils::parse_req_json::<ChangePasswordRequest>(req).await?;
+    utils::check_password_strength(&req_data.new_password)?;
+
+    // The old password can be guessed here too
+    let class_key = lockout::account_key("class", &class_id);
+    lockout::check(pool, &[ip_key.clone(), class_key.clone()]).await?;
+
+    let hashed_password =
+        sqlx::query_scalar!("SELECT password_hash FROM classroom WHERE id=$1", class_id)
+            .fetch_one(pool)
+            .await?;
+    if !utils::verify_password(req_data.old_password, hashed_password)? {
+        metrics::record_login_failure("class");
+        lockout::record_failure(pool, &[ip_key, class_key]).await?;
+        return Err(ApiError::IncorrectPassword);
+    }
+
+    if !utils::set_class_password(pool, &class_id, req_data.new_password).await? {
+        return Err(ApiError::NotFound);
+    }
+
+    audit::record(
+        pool,
+        &actor,
+        "classroom.change_password",
+        "classroom",
+        &class_id,
+        None,
+        None,
+    )
     .await;
 
-    let hashed_password = match result {
-        Ok(v) => match v {
-            Some(hash) => hash,
-            None => {
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Specified school_id is not found.".to_string(),
-                )
-            }
-        },
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+    issue_class_token(pool, &class_id, in_body).await
+}
+
+const RESET_CODE_LEN: usize = 10;
+
+#[derive(Serialize)]
+struct ResetCodeResponse {
+    code: String,
+    expires_at: String,
+}
+
+// Issue a one-time code to reset the password. The teacher of the classroom or administrators can issue it.
+pub async fn handler_reset_code(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = get_managed_class_id(pool, &req).await?;
+    let actor = audit::get_actor(pool, &req).await;
+    let created_by = if utils::is_admin(&req) {
+        None
+    } else {
+        Some(
+            utils::get_teacher_info_from_token(pool, &req)
+                .await?
+                .teacher_id,
+        )
     };
 
-    // Check password
-    let result = utils::verify_password(login_data.password, hashed_password);
-    match result {
-        Ok(verified) => {
-            if !verified {
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Incorrect password".to_string(),
-                );
-            }
-        }
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM classroom WHERE id=$1 AND archived_at IS NULL)",
+        class_id
+    )
+    .fetch_one(pool)
+    .await?;
+    if exists <= 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    // Only the latest code is valid
+    let code = utils::generate_code(RESET_CODE_LEN);
+    let code_hash = utils::compute_password_hash(code.clone());
+    let expires_at = Utc::now() + chrono::Duration::minutes(CONFIG.reset_code_minutes);
+    let expires_at_unix = expires_at.timestamp();
+    sqlx::query!(
+        "REPLACE INTO password_reset(class_id, code_hash, expires_at, created_by) VALUES($1, $2, $3, $4)",
+        class_id,
+        code_hash,
+        expires_at_unix,
+        created_by
+    )
+    .execute(pool)
+    .await?;
+
+    let expires_at = expires_at
+        .with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+
+    // The code itself is secret
+    audit::record(
+        pool,
+        &actor,
+        "classroom.reset_code",
+        "classroom",
+        &class_id,
+        None,
+        Some(json!({ "expires_at": expires_at })),
+    )
+    .await;
+
+    utils::response_struct_json(StatusCode::OK, &ResetCodeResponse { code, expires_at })
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordRequest {
+    class_id: String,
+    code: String,
+    new_password: String,
+}
+
+// Set the new password with the reset code, and sign in the device
+pub async fn handler_reset_password(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    reset_password(req, false).await
+}
+
+// Reset which returns the token in the body to send it as the bearer token
+pub async fn handler_reset_password_token(
+    req: Request<hyper::body::Incoming>,
+) -> utils::HandlerResponse {
+    reset_password(req, true).await
+}
+
+async fn reset_password(
+    req: Request<hyper::body::Incoming>,
+    in_body: bool,
+) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let ip_key = lockout::ip_key(utils::get_client_ip(&req));
+    let actor = audit::get_actor(pool, &req).await;
+
+    let req_data = utils::parse_req_json::<ResetPasswordRequest>(req).await?;
+    utils::check_password_strength(&req_data.new_password)?;
+
+    let class_key = lockout::account_key("class", &req_data.class_id);
+    lockout::check(pool, &[ip_key.clone(), class_key.clone()]).await?;
+
+    let reset = sqlx::query!(
+        "SELECT code_hash, expires_at FROM password_reset WHERE class_id=$1",
+        req_data.class_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let valid = match reset {
+        Some(reset) if reset.expires_at > Utc::now().timestamp() => {
+            utils::verify_password(req_data.code.trim().to_uppercase(), reset.code_hash)?
         }
+        _ => false,
+    };
+    if !valid {
+        metrics::record_login_failure("class");
+        lockout::record_failure(pool, &[ip_key, class_key]).await?;
+        return Err(ApiError::InvalidField("code"));
     }
 
-    let token = Ulid::new().to_string();
+    // The code is removed with the old sessions
+    if !utils::set_class_password(pool, &req_data.class_id, req_data.new_password).await? {
+        return Err(ApiError::NotFound);
+    }
+    lockout::reset(pool, &class_key).await?;
+
+    audit::record(
+        pool,
+        &actor,
+        "classroom.reset_password",
+        "classroom",
+        &req_data.class_id,
+        None,
+        None,
+    )
+    .await;
+
+    issue_class_token(pool, &req_data.class_id, in_body).await
+}
+
+// Classroom managed by the request. Administrators specify it with the `id` query.
+async fn get_managed_class_id(
+    pool: &Pool<Sqlite>,
+    req: &Request<hyper::body::Incoming>,
+) -> ApiResult<String> {
+    let class_id = utils::get_query_param(req, "id");
+    if utils::is_admin(req) {
+        return class_id.ok_or(ApiError::InvalidParams);
+    }
+
+    let teacher = utils::get_teacher_info_from_token(pool, req).await?;
+    if class_id.is_some_and(|class_id| class_id != teacher.class_id) {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(teacher.class_id)
+}
+
+#[derive(Deserialize)]
+struct UpdateRequest {
+    grade: Option<i64>,
+    name: Option<String>,
+}
+
+pub async fn handler_update(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = get_managed_class_id(pool, &req).await?;
+    let actor = audit::get_actor(pool, &req).await;
+
+    let update_data = utils::parse_req_json::<UpdateRequest>(req).await?;
+
+    let before = sqlx::query!(
+        "SELECT grade, name FROM classroom WHERE id=$1 AND archived_at IS NULL",
+        class_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
     let result = sqlx::query!(
-        "INSERT INTO class_token VALUES($1, $2)",
-        token,
-        login_data.class_id
+        "UPDATE classroom SET grade=COALESCE($1, grade), name=COALESCE($2, name) WHERE id=$3 AND archived_at IS NULL",
+        update_data.grade,
+        update_data.name,
+        class_id
     )
     .execute(pool)
+    .await
+    .map_err(|e| ApiError::from_insert(e, "classroom"))?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    audit::record(
+        pool,
+        &actor,
+        "classroom.update",
+        "classroom",
+        &class_id,
+        Some(json!({ "grade": before.grade, "name": before.name })),
+        Some(json!({
+            "grade": update_data.grade.unwrap_or(before.grade),
+            "name": update_data.name.unwrap_or(before.name),
+        })),
+    )
     .await;
 
-    if let Err(e) = result {
-        println!("{}", e.to_string());
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    utils::response_empty(StatusCode::OK)
+}
+
+// Archive the classroom, or delete all the data of it with `mode=purge`
+pub async fn handler_delete(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_query_param(&req, "id").ok_or(ApiError::InvalidParams)?;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let (found, action) = match utils::get_query_param(&req, "mode").as_deref() {
+        None | Some("archive") => (
+            utils::archive_classroom(pool, &class_id).await?,
+            "classroom.archive",
+        ),
+        Some("purge") => (
+            utils::delete_classroom(pool, &class_id).await?,
+            "classroom.purge",
+        ),
+        Some(_) => return Err(ApiError::InvalidField("mode")),
+    };
+
+    if !found {
+        return Err(ApiError::NotFound);
     }
 
-    let token_cookie = utils::create_cookie("class_token".to_string(), token);
+    audit::record(pool, &actor, action, "classroom", &class_id, None, None).await;
 
-    let mut response = utils::response_empty(StatusCode::OK)?;
-    response
-        .headers_mut()
-        .append(SET_COOKIE, HeaderValue::from_str(token_cookie.as_str())?);
-    Ok(response)
+    utils::response_empty(StatusCode::OK)
 }
 
-pub async fn handler_logout(_: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+pub async fn handler_logout(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    utils::revoke_tokens(pool, &req).await?;
+
     let mut response = utils::response_empty(StatusCode::OK)?;
     response.headers_mut().append(
         HeaderName::from_static("clear-site-data"),
@@ -183,37 +466,36 @@ pub async fn handler_logout(_: Request<hyper::body::Incoming>) -> utils::Handler
     Ok(response)
 }
 
+#[derive(Serialize)]
+struct NowStatusResponse {
+    #[serde(flatten)]
+    day_status: Option<DayStatus>,
+    streaks: ClassStreaks,
+}
+
 pub async fn handler_get_now_status(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let class_id = {
-        let result = utils::get_class_id_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
-        }
-    };
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
 
-    let result = sqlx::query_as!(
+    // Fields of the day status are omitted until something is recorded today
+    let day_status = sqlx::query_as!(
         DayStatus,
         "SELECT * FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
         class_id
     )
     .fetch_optional(pool)
-    .await;
+    .await?;
 
-    let day_status = match result {
-        Ok(v) => match v {
-            Some(_day_status) => _day_status,
-            None => return utils::response_json(StatusCode::OK, "{}".to_string()),
-        },
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let streaks = streak::get_class_streaks(pool, &class_id).await?;
 
-    utils::response_struct_json::<DayStatus>(StatusCode::OK, &day_status)
+    utils::response_struct_json(
+        StatusCode::OK,
+        &NowStatusResponse {
+            day_status,
+            streaks,
+        },
+    )
 }
 
 pub async fn handler_day_status_history(
@@ -221,37 +503,139 @@ pub async fn handler_day_status_history(
 ) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let class_id = {
-        let result = utils::get_class_id_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
+
+    day_status_history(pool, &class_id).await
+}
+
+// History of the classroom in the path. The classroom itself, its teacher and administrators can see it.
+pub async fn handler_history(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_path_param(&req, "id").ok_or(ApiError::InvalidParams)?;
+
+    if !utils::is_admin(&req) {
+        let allowed = match utils::get_class_id_from_token(pool, &req).await {
+            Ok(own_id) => own_id == class_id,
+            Err(_) => {
+                utils::get_teacher_info_from_token(pool, &req)
+                    .await?
+                    .class_id
+                    == class_id
+            }
+        };
+        if !allowed {
+            return Err(ApiError::Forbidden);
         }
-    };
+    }
 
-    let result = sqlx::query_as!(
-        DayStatus,
-        "SELECT * FROM day_status WHERE class_id=$1 AND date >= date('now', 'localtime', '-30 days')",
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM classroom WHERE id=$1)",
         class_id
     )
+    .fetch_one(pool)
+    .await?;
+    if exists <= 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    day_status_history(pool, &class_id).await
+}
+
+async fn day_status_history(pool: &Pool<Sqlite>, class_id: &str) -> utils::HandlerResponse {
+    let year = sqlx::query_scalar!("SELECT academic_year FROM classroom WHERE id=$1", class_id)
+        .fetch_one(pool)
+        .await?;
+
+    // History of the classroom is limited in the academic year
+    let (year_start, year_end) = utils::academic_year_period(year);
+
+    let day_status_list = sqlx::query_as!(
+        DayStatus,
+        "SELECT * FROM day_status WHERE class_id=$1 AND date >= date('now', 'localtime', '-30 days') AND date BETWEEN $2 AND $3",
+        class_id,
+        year_start,
+        year_end
+    )
     .fetch_all(pool)
-    .await;
+    .await?;
 
-    let day_status_list = match result {
-        Ok(v) => v,
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+    utils::response_json(StatusCode::OK, json!(day_status_list).to_string())
+}
+
+// Classroom of the entry, and the teacher if any. Teachers can enter the data of their classroom
+// with the teacher token alone, since the bearer clients send only one token.
+async fn get_entry_class(
+    pool: &Pool<Sqlite>,
+    req: &Request<hyper::body::Incoming>,
+) -> ApiResult<(String, ApiResult<utils::TeacherInfo>)> {
+    let teacher = utils::get_teacher_info_from_token(pool, req).await;
+    let class_id = match (utils::get_class_id_from_token(pool, req).await, &teacher) {
+        (Ok(class_id), _) => class_id,
+        (Err(_), Ok(teacher)) => teacher.class_id.clone(),
+        (Err(e), Err(_)) => return Err(e),
+    };
+    Ok((class_id, teacher))
+}
+
+// Resolve the date of the entry. Past dates can be corrected by the teacher of the class.
+fn resolve_entry_date(
+    date: Option<&str>,
+    class_id: &str,
+    teacher: ApiResult<utils::TeacherInfo>,
+) -> ApiResult<(String, Option<String>)> {
+    let today = Local::now().date_naive();
+    let date = match date {
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|_| ApiError::InvalidField("date"))?,
+        None => today,
     };
-    println!("c: {}", day_status_list.len());
 
-    utils::response_json(StatusCode::OK, json!(day_status_list).to_string())
+    if date == today {
+        return Ok((date.to_string(), None));
+    }
+
+    if date > today || (today - date).num_days() > CONFIG.backdate_days {
+        return Err(ApiError::OutOfCorrectionPeriod);
+    }
+
+    let teacher = teacher?;
+    if teacher.class_id != class_id {
+        return Err(ApiError::Forbidden);
+    }
+
+    Ok((date.to_string(), Some(teacher.teacher_id)))
+}
+
+async fn log_correction(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    date: &str,
+    teacher_id: &str,
+    field: &str,
+    before: Option<i64>,
+    after: Option<i64>,
+) -> Result<()> {
+    let id = Ulid::new().to_string();
+    sqlx::query!(
+        "INSERT INTO correction_log VALUES($1, $2, $3, $4, $5, $6, $7, datetime('now', 'localtime'))",
+        id,
+        class_id,
+        date,
+        teacher_id,
+        field,
+        before,
+        after
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
 }
 
 #[derive(Deserialize)]
 struct RegistAttendanceRequest {
     attendees: i64,
+    date: Option<String>,
 }
 
 pub async fn handler_regist_attendance(
@@ -259,108 +643,106 @@ pub async fn handler_regist_attendance(
 ) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let class_id = {
-        let result = utils::get_class_id_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
-        }
-    };
+    let (class_id, teacher) = get_entry_class(pool, &req).await?;
 
-    let req_data = {
-        let result = utils::parse_req_json::<RegistAttendanceRequest>(req).await;
-        match result {
-            Ok(r) => r,
-            Err(e) => {
-                println!("{}", e.to_string());
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid params".to_string(),
-                );
-            }
-        }
-    };
+    let actor = audit::get_actor(pool, &req).await;
+
+    let req_data = utils::parse_req_json::<RegistAttendanceRequest>(req).await?;
 
-    let result = sqlx::query_as!(
+    let (date, teacher_id) = resolve_entry_date(req_data.date.as_deref(), &class_id, teacher)?;
+
+    let prev_status = sqlx::query_as!(
         DayStatus,
-        "SELECT * FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
-        class_id
+        "SELECT * FROM day_status WHERE class_id=$1 AND date=$2",
+        class_id,
+        date
     )
     .fetch_optional(pool)
-    .await;
-
-    let prev_status = match result {
-        Ok(v) => match v {
-            Some(_prev_status) => _prev_status,
-            None => DayStatus {
-                class_id: "".to_string(),
-                point: 0,
-                attend: None,
-                leftovers: None,
-                date: "".to_string(),
-            },
-        },
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-
-    let result = sqlx::query!(
-        "INSERT INTO day_status values ($1, 0, $2, date('now', 'localtime'), NULL) ON CONFLICT(class_id, date) DO UPDATE SET attend = $2",
+    .await?
+    .unwrap_or(DayStatus {
+        class_id: "".to_string(),
+        point: 0,
+        attend: None,
+        leftovers: None,
+        date: "".to_string(),
+        servings: None,
+    });
+
+    sqlx::query!(
+        "INSERT INTO day_status(class_id, point, attend, date) VALUES ($1, 0, $2, $3) ON CONFLICT(class_id, date) DO UPDATE SET attend = $2",
         class_id,
-        req_data.attendees
+        req_data.attendees,
+        date
     )
     .execute(pool)
-    .await;
-
-    if let Err(e) = result {
-        eprintln!("{}", e);
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-    }
+    .await?;
 
-    let result = sqlx::query_as!(
+    let status = sqlx::query_as!(
         DayStatus,
-        "SELECT * FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
-        class_id
+        "SELECT * FROM day_status WHERE class_id=$1 AND date=$2",
+        class_id,
+        date
     )
-    .fetch_optional(pool)
-    .await;
+    .fetch_one(pool)
+    .await?;
 
-    let status = match result {
-        Ok(v) => match v {
-            Some(_prev_status) => _prev_status,
-            None => return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR),
-        },
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let baseline = utils::get_leftovers_baseline(pool, &class_id, &status.date).await?;
 
     let point = std::cmp::max(
         0,
-        calc_leftovers_point(&prev_status, &status) + status.point,
+        calc_leftovers_point(&prev_status, &status, baseline) + status.point,
     );
 
-    let result = sqlx::query!(
-        "UPDATE day_status SET point = $1 WHERE class_id=$2 AND date=date('now', 'localtime')",
+    sqlx::query!(
+        "UPDATE day_status SET point = $1 WHERE class_id=$2 AND date=$3",
         point,
-        class_id
+        class_id,
+        date
     )
     .execute(pool)
+    .await?;
+
+    if let Some(teacher_id) = teacher_id {
+        let corrections = [
+            ("attend", prev_status.attend, status.attend),
+            ("point", Some(prev_status.point), Some(point)),
+        ];
+        for (field, before, after) in corrections {
+            log_correction(pool, &class_id, &date, &teacher_id, field, before, after).await?;
+        }
+    }
+
+    audit::record(
+        pool,
+        &actor,
+        "classroom.attendance",
+        "classroom",
+        &class_id,
+        Some(json!({ "date": date, "attend": prev_status.attend, "point": prev_status.point })),
+        Some(json!({ "date": date, "attend": status.attend, "point": point })),
+    )
     .await;
 
-    if result.is_err() {
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-    }
+    let achievements = achievement::evaluate_or_empty(pool, &class_id, &achievement::BADGES).await;
+    utils::response_struct_json(StatusCode::OK, &AwardsResponse { achievements })
+}
 
-    utils::response_empty(StatusCode::OK)
+#[derive(Deserialize)]
+struct LeftoverItemRequest {
+    dish: String,
+    category: Option<String>,
+    amount: f64,
+    unit: LeftoverUnit,
+    photo: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct RegistLeftoversRequest {
-    leftovers: i64,
+    servings: Option<i64>,
+    #[serde(default)]
+    items: Vec<LeftoverItemRequest>,
+    leftovers: Option<i64>, // Total grams for clients which don't send items
+    date: Option<String>,
 }
 
 pub async fn handler_regist_leftovers(
@@ -368,258 +750,310 @@ pub async fn handler_regist_leftovers(
 ) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let class_id = {
-        let result = utils::get_class_id_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
-        }
-    };
+    let (class_id, teacher) = get_entry_class(pool, &req).await?;
 
-    let req_data = {
-        let result = utils::parse_req_json::<RegistLeftoversRequest>(req).await;
-        match result {
-            Ok(r) => r,
-            Err(e) => {
-                println!("{}", e.to_string());
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid params".to_string(),
-                );
-            }
-        }
-    };
+    let actor = audit::get_actor(pool, &req).await;
+
+    let req_data = utils::parse_req_json::<RegistLeftoversRequest>(req).await?;
+
+    let (date, teacher_id) = resolve_entry_date(req_data.date.as_deref(), &class_id, teacher)?;
 
-    let result = sqlx::query_as!(
+    let prev_status = sqlx::query_as!(
         DayStatus,
-        "SELECT * FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
-        class_id
+        "SELECT * FROM day_status WHERE class_id=$1 AND date=$2",
+        class_id,
+        date
     )
     .fetch_optional(pool)
-    .await;
-
-    let prev_status = match result {
-        Ok(v) => match v {
-            Some(_prev_status) => _prev_status,
-            None => DayStatus {
-                class_id: "".to_string(),
-                point: 0,
-                attend: None,
-                leftovers: None,
-                date: "".to_string(),
-            },
-        },
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    .await?
+    .unwrap_or(DayStatus {
+        class_id: "".to_string(),
+        point: 0,
+        attend: None,
+        leftovers: None,
+        date: "".to_string(),
+        servings: None,
+    });
+
+    let items = if req_data.items.is_empty() {
+        match req_data.leftovers {
+            Some(leftovers) => vec![LeftoverItemRequest {
+                dish: "total".to_string(),
+                category: None,
+                amount: leftovers as f64,
+                unit: LeftoverUnit::G,
+                photo: None,
+            }],
+            None => return Err(ApiError::InvalidParams),
         }
+    } else {
+        req_data.items
     };
 
-    let result = sqlx::query!(
-        "INSERT INTO day_status values ($1, 0, NULL, date('now', 'localtime'), $2) ON CONFLICT(class_id, date) DO UPDATE SET leftovers = $2",
+    if items
+        .iter()
+        .any(|item| !item.amount.is_finite() || item.amount < 0.0)
+        || req_data.servings.is_some_and(|servings| servings < 0)
+    {
+        return Err(ApiError::InvalidParams);
+    }
+
+    let mut tx = pool.begin().await?;
+
+    // Replace the records of today
+    sqlx::query!(
+        "DELETE FROM leftover_item WHERE class_id=$1 AND date=$2",
         class_id,
-        req_data.leftovers
+        date
     )
-    .execute(pool)
-    .await;
-
-    if let Err(e) = result {
-        eprintln!("{}", e);
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    .execute(&mut *tx)
+    .await?;
+
+    for item in items.iter() {
+        let id = Ulid::new().to_string();
+        let unit = item.unit.as_str();
+        let grams = item.unit.to_grams(item.amount);
+        sqlx::query!(
+            "INSERT INTO leftover_item VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+            id,
+            class_id,
+            date,
+            item.dish,
+            item.category,
+            item.amount,
+            unit,
+            grams,
+            req_data.servings,
+            item.photo
+        )
+        .execute(&mut *tx)
+        .await?;
     }
 
-    let result = sqlx::query_as!(
+    // Total of the day is derived from the records
+    sqlx::query!(
+        "INSERT INTO day_status(class_id, point, date, leftovers, servings)
+        VALUES ($1, 0, $3,
+            (SELECT CAST(ROUND(SUM(grams)) AS INTEGER) FROM leftover_item WHERE class_id=$1 AND date=$3), $2)
+        ON CONFLICT(class_id, date) DO UPDATE SET leftovers = excluded.leftovers, servings = COALESCE(excluded.servings, servings)",
+        class_id,
+        req_data.servings,
+        date
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let status = sqlx::query_as!(
         DayStatus,
-        "SELECT * FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
-        class_id
+        "SELECT * FROM day_status WHERE class_id=$1 AND date=$2",
+        class_id,
+        date
     )
-    .fetch_optional(pool)
-    .await;
+    .fetch_one(pool)
+    .await?;
 
-    let status = match result {
-        Ok(v) => match v {
-            Some(_prev_status) => _prev_status,
-            None => return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR),
-        },
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let baseline = utils::get_leftovers_baseline(pool, &class_id, &status.date).await?;
 
     let point = std::cmp::max(
         0,
-        calc_leftovers_point(&prev_status, &status) + status.point,
+        calc_leftovers_point(&prev_status, &status, baseline) + status.point,
     );
 
-    let result = sqlx::query!(
-        "UPDATE day_status SET point = $1 WHERE class_id=$2 AND date=date('now', 'localtime')",
+    sqlx::query!(
+        "UPDATE day_status SET point = $1 WHERE class_id=$2 AND date=$3",
         point,
-        class_id
+        class_id,
+        date
     )
     .execute(pool)
-    .await;
-
-    if result.is_err() {
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    .await?;
+
+    if let Some(teacher_id) = teacher_id {
+        let corrections = [
+            ("leftovers", prev_status.leftovers, status.leftovers),
+            ("point", Some(prev_status.point), Some(point)),
+        ];
+        for (field, before, after) in corrections {
+            log_correction(pool, &class_id, &date, &teacher_id, field, before, after).await?;
+        }
     }
 
-    utils::response_empty(StatusCode::OK)
-}
+    audit::record(
+        pool,
+        &actor,
+        "classroom.leftovers",
+        "classroom",
+        &class_id,
+        Some(json!({
+            "date": date,
+            "leftovers": prev_status.leftovers,
+            "servings": prev_status.servings,
+            "point": prev_status.point,
+        })),
+        Some(json!({
+            "date": date,
+            "leftovers": status.leftovers,
+            "servings": status.servings,
+            "point": point,
+        })),
+    )
+    .await;
 
-#[derive(Serialize)]
-struct Classroom {
-    id: String,
-    school_id: String,
-    grade: i64,
-    name: String,
+    let achievements = achievement::evaluate_or_empty(pool, &class_id, &achievement::BADGES).await;
+    utils::response_struct_json(StatusCode::OK, &AwardsResponse { achievements })
 }
 
 #[derive(Serialize)]
-struct School {
+struct LeftoverItem {
     id: String,
-    name: String,
+    dish: String,
+    category: Option<String>,
+    amount: f64,
+    unit: String,
+    grams: f64,
+    servings: Option<i64>,
+    photo: Option<String>,
 }
 
 #[derive(Serialize)]
-struct AllClassrooms {
-    schools: Vec<School>,
-    classrooms: Vec<Classroom>,
+struct LeftoversResponse {
+    leftovers: Option<i64>,
+    servings: Option<i64>,
+    baseline: f64, // Expected leftovers per serving (gram)
+    menu: Vec<String>,
+    items: Vec<LeftoverItem>,
 }
 
-pub async fn handler_get_all(_: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+pub async fn handler_get_leftovers(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let result = sqlx::query_as!(School, "SELECT * FROM school")
-        .fetch_all(pool)
-        .await;
-
-    let schools = match result {
-        Ok(v) => v,
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
 
-    let result = sqlx::query_as!(
-        Classroom,
-        "SELECT id, school_id, grade, name FROM classroom"
+    let items = sqlx::query_as!(
+        LeftoverItem,
+        "SELECT id, dish, category, amount, unit, grams, servings, photo FROM leftover_item
+        WHERE class_id=$1 AND date=date('now', 'localtime')",
+        class_id
     )
     .fetch_all(pool)
-    .await;
+    .await?;
 
-    let classrooms = match result {
-        Ok(v) => v,
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let status = sqlx::query_as!(
+        DayStatus,
+        "SELECT * FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
+        class_id
+    )
+    .fetch_optional(pool)
+    .await?;
 
-    let all = AllClassrooms {
-        schools,
-        classrooms,
+    let (leftovers, servings) = match status {
+        Some(status) => (status.leftovers, status.servings),
+        None => (None, None),
     };
 
-    utils::response_struct_json(StatusCode::OK, &all)
+    let menu = sqlx::query_scalar!(
+        "SELECT dish FROM lunch_menu
+        WHERE school_id=(SELECT school_id FROM classroom WHERE id=$1) AND date=date('now', 'localtime')",
+        class_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let baseline = utils::get_leftovers_baseline(pool, &class_id, &utils::today()).await?;
+
+    utils::response_struct_json(
+        StatusCode::OK,
+        &LeftoversResponse {
+            leftovers,
+            servings,
+            baseline,
+            menu,
+            items,
+        },
+    )
 }
 
 #[derive(Serialize)]
 struct SensorResponse {
     point: i64,
+    scored: bool,
+    achievements: Vec<Award>,
 }
 
 pub async fn handler_sensor(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let class_id = {
-        let result = utils::get_class_id_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
-        }
-    };
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
 
-    let req_data = {
-        let result = utils::parse_req_json::<Sensor>(req).await;
-        match result {
-            Ok(r) => r,
-            Err(e) => {
-                println!("{}", e.to_string());
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid params".to_string(),
-                );
-            }
-        }
-    };
+    let actor = audit::get_actor(pool, &req).await.into_device();
+
+    let req_data = utils::parse_req_json::<Sensor>(req).await?;
+    metrics::record_sensor_reading(&class_id);
 
-    let result = sqlx::query_scalar!(
+    let latest_time = sqlx::query_scalar!(
         "SELECT time FROM latest_sensor_time WHERE class_id=$1",
         class_id
     )
     .fetch_optional(pool)
-    .await;
+    .await?;
 
-    let time_diff_msec = match result {
-        Ok(time_option) => match time_option {
-            Some(time) => {
-                println!("{}", time.as_str());
-                let latest_result = utils::parse_str_time(time.as_str());
-                match latest_result {
-                    Ok(latest) => {
-                        println!("{} : {}", Utc::now(), latest);
-                        (Utc::now() - latest).num_milliseconds()
-                    }
-                    Err(e) => {
-                        println!("e0: {}", e.to_string());
-                        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-                    }
-                }
-            }
-            None => 0,
-        },
-        Err(e) => {
-            println!("e1: {}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    let time_diff_msec = match latest_time {
+        Some(time) => {
+            let latest = utils::parse_str_time(time.as_str())?;
+            (Utc::now() - latest).num_milliseconds()
         }
+        None => 0,
     };
 
     // Update latest time
-    let result = sqlx::query!(
+    sqlx::query!(
         "REPLACE INTO latest_sensor_time values($1, datetime('now', 'localtime'))",
         class_id
     )
     .execute(pool)
-    .await;
-
-    if let Err(e) = result {
-        println!("e2: {}", e.to_string());
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    .await?;
+
+    // Points are scored only in the school hours
+    let school_id = sqlx::query_scalar!("SELECT school_id FROM classroom WHERE id=$1", class_id)
+        .fetch_one(pool)
+        .await?;
+
+    if !calendar::is_school_time(pool, &school_id, Local::now().naive_local()).await? {
+        let point = sqlx::query_scalar!(
+            "SELECT point FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
+            class_id
+        )
+        .fetch_optional(pool)
+        .await?;
+        return utils::response_struct_json::<SensorResponse>(
+            StatusCode::OK,
+            &SensorResponse {
+                point: point.unwrap_or(0),
+                scored: false,
+                achievements: Vec::new(),
+            },
+        );
     }
 
     // Calc point
     let airconditionaer_point = utils::calc_airconditionaer_point(&req_data, time_diff_msec);
-    println!("airp :{}", airconditionaer_point);
-
     let lux_point = utils::calc_lux_point(&req_data, time_diff_msec);
-    println!("luxp :{}", lux_point);
+    tracing::debug!(
+        class_id,
+        time_diff_msec,
+        airconditioner_point = airconditionaer_point,
+        lux_point,
+        "sensor points"
+    );
 
-    let result = sqlx::query_scalar!(
+    let point_option = sqlx::query_scalar!(
         "SELECT point FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
         class_id
     )
     .fetch_optional(pool)
-    .await;
-
-    let point_option = match result {
-        Ok(v) => v,
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    .await?;
 
     let point_diff = airconditionaer_point;
 
@@ -629,27 +1063,145 @@ pub async fn handler_sensor(req: Request<hyper::body::Incoming>) -> utils::Handl
         None => std::cmp::max(0, point_diff),
     };
 
-    let result = sqlx::query!(
-        "INSERT INTO day_status values ($1, $2, NULL, date('now', 'localtime'), NULL) ON CONFLICT(class_id, date) DO UPDATE SET point = $2",
+    sqlx::query!(
+        "INSERT INTO day_status(class_id, point, date) VALUES ($1, $2, date('now', 'localtime')) ON CONFLICT(class_id, date) DO UPDATE SET point = $2",
         class_id,
         result_point
     )
     .execute(pool)
-    .await;
-
-    if let Err(e) = result {
-        eprintln!("{}", e);
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    .await?;
+
+    // Devices post continuously, so the changes of the points are put together by the day
+    if point_option != Some(result_point) {
+        audit::record_daily(
+            pool,
+            &actor,
+            "classroom.sensor",
+            "classroom",
+            &class_id,
+            point_option.map(|point| json!({ "point": point })),
+            Some(json!({ "point": result_point })),
+        )
+        .await;
     }
 
+    // Record estimated saving for the impact report
+    let airconditioner_kwh = utils::calc_airconditioner_saving(&req_data, time_diff_msec);
+    let lighting_kwh = utils::calc_lighting_saving(&req_data, time_diff_msec);
+    sqlx::query!(
+        "INSERT INTO day_energy VALUES ($1, date('now', 'localtime'), $2, $3) ON CONFLICT(class_id, date)
+        DO UPDATE SET airconditioner_kwh = airconditioner_kwh + $2, lighting_kwh = lighting_kwh + $3",
+        class_id,
+        airconditioner_kwh,
+        lighting_kwh
+    )
+    .execute(pool)
+    .await?;
+
+    // Readings which don't change the points can't give the badges
+    let achievements = if point_option != Some(result_point) {
+        achievement::evaluate_or_empty(pool, &class_id, &achievement::POINT_BADGES).await
+    } else {
+        Vec::new()
+    };
     utils::response_struct_json::<SensorResponse>(
         StatusCode::OK,
         &SensorResponse {
             point: result_point,
+            scored: true,
+            achievements,
+        },
+    )
+}
+
+pub async fn handler_impact(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
+
+    let (from, to) = utils::get_query_period(&req).ok_or(ApiError::InvalidField("period"))?;
+
+    let school_id = sqlx::query_scalar!("SELECT school_id FROM classroom WHERE id=$1", class_id)
+        .fetch_one(pool)
+        .await?;
+
+    let impact = utils::calc_impact(pool, &school_id, &[class_id], &from, &to).await?;
+    utils::response_struct_json(StatusCode::OK, &impact)
+}
+
+#[derive(Serialize)]
+struct SummaryResponse {
+    from: String,
+    to: String,
+    school_days: i64,
+    point: i64,
+    leftovers: i64,
+    point_per_school_day: f64,
+    leftovers_per_school_day: f64, // gram
+}
+
+// Aggregates of the period, normalized per school day so that holidays don't lower them
+pub async fn handler_summary(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
+
+    let (from, to) = utils::get_query_period(&req).ok_or(ApiError::InvalidField("period"))?;
+
+    let totals = sqlx::query!(
+        r#"SELECT school_id AS "school_id!", CAST(COALESCE(SUM(point), 0) AS INTEGER) AS "point!: i64", CAST(COALESCE(SUM(leftovers), 0) AS INTEGER) AS "leftovers!: i64"
+        FROM classroom LEFT JOIN day_status ON day_status.class_id=classroom.id AND day_status.date BETWEEN $2 AND $3
+        WHERE classroom.id=$1"#,
+        class_id,
+        from,
+        to
+    )
+    .fetch_one(pool)
+    .await?;
+
+    // The period is validated by get_query_period
+    let (from_date, to_date) = match (
+        NaiveDate::parse_from_str(&from, "%Y-%m-%d"),
+        NaiveDate::parse_from_str(&to, "%Y-%m-%d"),
+    ) {
+        (Ok(from_date), Ok(to_date)) => (from_date, to_date),
+        _ => return Err(ApiError::InvalidField("period")),
+    };
+
+    let school_days =
+        calendar::count_school_days(pool, &totals.school_id, from_date, to_date).await?;
+
+    let per_school_day = |total: i64| {
+        if school_days > 0 {
+            total as f64 / school_days as f64
+        } else {
+            0.0
+        }
+    };
+
+    utils::response_struct_json(
+        StatusCode::OK,
+        &SummaryResponse {
+            point_per_school_day: per_school_day(totals.point),
+            leftovers_per_school_day: per_school_day(totals.leftovers),
+            from,
+            to,
+            school_days,
+            point: totals.point,
+            leftovers: totals.leftovers,
         },
     )
 }
 
+pub async fn handler_achievements(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
+
+    let achievements = achievement::get_awards(pool, &class_id).await?;
+    utils::response_struct_json(StatusCode::OK, &achievements)
+}
+
 struct ClassroomPoint {
     class_id: String,
     point: i64,
@@ -665,41 +1217,21 @@ struct PointResponse {
 pub async fn handler_point(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let class_id = {
-        let result = utils::get_class_id_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
-        }
-    };
-
-    let result = sqlx::query_scalar!(
-        "SELECT COUNT(*) FROM classroom WHERE classroom.school_id = (SELECT school_id FROM classroom WHERE id=$1)"
-    , class_id).fetch_one(pool).await;
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
 
-    let class_num = match result {
-        Ok(v) => v,
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let class_num = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM classroom
+        WHERE (school_id, academic_year) = (SELECT school_id, academic_year FROM classroom WHERE id=$1) AND archived_at IS NULL"
+    , class_id).fetch_one(pool).await?;
 
-    let result = sqlx::query_as!(
+    let point_list = sqlx::query_as!(
         ClassroomPoint,
         "SELECT class_id, point FROM day_status
         JOIN classroom ON classroom.id = day_status.class_id
-        WHERE date=date('now', 'localtime') AND classroom.school_id = (SELECT school_id FROM classroom WHERE id=$1)
+        WHERE date=date('now', 'localtime')
+            AND (classroom.school_id, classroom.academic_year) = (SELECT school_id, academic_year FROM classroom WHERE id=$1)
         ORDER BY point DESC"
-    , class_id).fetch_all(pool).await;
-
-    let point_list = match result {
-        Ok(v) => v,
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    , class_id).fetch_all(pool).await?;
 
     let mut rank = class_num;
     let mut point = 0;
@@ -728,27 +1260,18 @@ struct SetPointRequest {
 pub async fn handler_setpoint(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let class_id = {
-        let result = utils::get_class_id_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
-        }
-    };
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
 
-    let req_data = {
-        let result = utils::parse_req_json::<SetPointRequest>(req).await;
-        match result {
-            Ok(r) => r,
-            Err(e) => {
-                println!("{}", e.to_string());
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid params".to_string(),
-                );
-            }
-        }
-    };
+    let actor = audit::get_actor(pool, &req).await;
+
+    let req_data = utils::parse_req_json::<SetPointRequest>(req).await?;
+
+    let before = sqlx::query_scalar!(
+        "SELECT point FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
+        class_id
+    )
+    .fetch_optional(pool)
+    .await?;
 
     let result = sqlx::query!(
         "UPDATE day_status SET point=$1 WHERE class_id=$2 AND date=date('now', 'localtime')",
@@ -756,11 +1279,19 @@ pub async fn handler_setpoint(req: Request<hyper::body::Incoming>) -> utils::Han
         class_id
     )
     .execute(pool)
-    .await;
-
-    if let Err(e) = result {
-        println!("{}", e.to_string());
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    .await?;
+
+    if result.rows_affected() > 0 {
+        audit::record(
+            pool,
+            &actor,
+            "classroom.set_point",
+            "classroom",
+            &class_id,
+            before.map(|point| json!({ "point": point })),
+            Some(json!({ "point": req_data.point })),
+        )
+        .await;
     }
 
     utils::response_empty(StatusCode::OK)
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use hyper::{header, Request, Response, StatusCode};
+use serde::Serialize;
+
+use crate::{config::CONFIG, database, error::ApiError, health, metrics, utils};
+
+// Readiness checks give up after this, so that the probes don't hang
+const READY_TIMEOUT: Duration = Duration::from_secs(3);
+
+fn is_scraper(req: &Request<hyper::body::Incoming>) -> bool {
+    match (&CONFIG.metrics_token, utils::get_bearer_token(req)) {
+        (Some(metrics_token), Some(token)) => {
+            !metrics_token.is_empty()
+                && utils::secure_eq(metrics_token.as_bytes(), token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+// Metrics for Prometheus. The scrapers send METRICS_TOKEN or ADMIN_TOKEN as the bearer token.
+pub async fn handler_metrics(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    if !is_scraper(&req) && !utils::is_admin(&req) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let pool = &database::get_pool().await;
+
+    let body = metrics::render(pool).await?;
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(utils::full(body))?;
+    Ok(response)
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+}
+
+// Liveness. The process answers the requests.
+pub async fn handler_healthz(_req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    utils::response_struct_json(StatusCode::OK, &HealthResponse { status: "ok" })
+}
+
+#[derive(Serialize)]
+struct ReadyResponse {
+    status: &'static str, // ok, draining, or unavailable
+    version: &'static str,
+    uptime_secs: u64,
+    database: bool,
+    migrations: bool,
+}
+
+// Readiness. Fails while the database can't be used or the server is shutting down.
+pub async fn handler_readyz(_req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let checks = async {
+        let database = health::check_database(pool).await.is_ok();
+        let migrations = database && health::check_migrations(pool).await.unwrap_or(false);
+        (database, migrations)
+    };
+    let (database, migrations) = tokio::time::timeout(READY_TIMEOUT, checks)
+        .await
+        .unwrap_or((false, false));
+
+    let status = if health::is_draining() {
+        "draining"
+    } else if !database || !migrations {
+        "unavailable"
+    } else {
+        "ok"
+    };
+    let code = if status == "ok" {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    utils::response_struct_json(
+        code,
+        &ReadyResponse {
+            status,
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_secs: health::uptime().as_secs(),
+            database,
+            migrations,
+        },
+    )
+}
@@ -1,46 +1,650 @@
+use std::collections::{HashMap, HashSet};
+
 use anyhow::Result;
-use bytes::Bytes;
-use http_body_util::combinators::BoxBody;
-use hyper::{Request, Response, StatusCode};
-use serde::Deserialize;
+use chrono::NaiveDate;
+use hyper::{header::CONTENT_TYPE, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+use tokio::sync::mpsc;
+use tracing::Instrument;
 use ulid::Ulid;
 
-use crate::{database, utils};
+use crate::{
+    audit,
+    calendar::{self, CalendarEntry, DayKind},
+    database,
+    error::{ApiError, ApiResult},
+    export::{self, ExportFormat, ExportRequest},
+    utils,
+};
 
 #[derive(Deserialize)]
 struct CreateRequest {
     name: String,
 }
 
-pub async fn handler_create(
-    req: Request<hyper::body::Incoming>,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
-    let req_data = {
-        let result = utils::parse_req_json::<CreateRequest>(req).await;
-        match result {
-            Ok(r) => r,
-            Err(e) => {
-                println!("{}", e.to_string());
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid params".to_string(),
-                );
-            }
+#[derive(Serialize)]
+struct CreateResponse {
+    id: String,
+    join_code: String,
+}
+
+pub async fn handler_create(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let req_data = utils::parse_req_json::<CreateRequest>(req).await?;
+
+    let id = Ulid::new().to_string();
+    let join_code = utils::generate_join_code();
+
+    sqlx::query!(
+        "INSERT INTO school(id, name, join_code) VALUES($1, $2, $3)",
+        id,
+        req_data.name,
+        join_code
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| ApiError::from_insert(e, "join_code"))?;
+
+    audit::record(
+        pool,
+        &actor,
+        "school.create",
+        "school",
+        &id,
+        None,
+        Some(json!({ "name": req_data.name })),
+    )
+    .await;
+
+    utils::response_struct_json(StatusCode::OK, &CreateResponse { id, join_code })
+}
+
+#[derive(Serialize)]
+struct JoinSchool {
+    id: String,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct JoinClassroom {
+    id: String,
+    academic_year: i64,
+    grade: i64,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct ClassroomsResponse {
+    school: JoinSchool,
+    classrooms: Vec<JoinClassroom>,
+}
+
+// Classrooms of the school with the join code, for the login screen
+pub async fn handler_classrooms(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let join_code = utils::get_path_param(&req, "code")
+        .ok_or(ApiError::InvalidParams)?
+        .trim()
+        .to_uppercase();
+
+    let pool = &database::get_pool().await;
+
+    let school = sqlx::query_as!(
+        JoinSchool,
+        "SELECT id, name FROM school WHERE join_code=$1 AND archived_at IS NULL",
+        join_code
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    let classrooms = sqlx::query_as!(
+        JoinClassroom,
+        "SELECT id, academic_year, grade, name FROM classroom WHERE school_id=$1 AND archived_at IS NULL ORDER BY grade, name",
+        school.id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    utils::response_struct_json(StatusCode::OK, &ClassroomsResponse { school, classrooms })
+}
+
+#[derive(Deserialize)]
+struct UpdateRequest {
+    name: String,
+}
+
+pub async fn handler_update(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let school_id = utils::get_query_param(&req, "id").ok_or(ApiError::InvalidParams)?;
+
+    let pool = &database::get_pool().await;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let req_data = utils::parse_req_json::<UpdateRequest>(req).await?;
+
+    let before = sqlx::query_scalar!(
+        "SELECT name FROM school WHERE id=$1 AND archived_at IS NULL",
+        school_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    let result = sqlx::query!(
+        "UPDATE school SET name=$1 WHERE id=$2 AND archived_at IS NULL",
+        req_data.name,
+        school_id
+    )
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    audit::record(
+        pool,
+        &actor,
+        "school.update",
+        "school",
+        &school_id,
+        Some(json!({ "name": before })),
+        Some(json!({ "name": req_data.name })),
+    )
+    .await;
+
+    utils::response_empty(StatusCode::OK)
+}
+
+// Archive the school, or delete all the data of it with `mode=purge`
+pub async fn handler_delete(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let school_id = utils::get_query_param(&req, "id").ok_or(ApiError::InvalidParams)?;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let (found, action) = match utils::get_query_param(&req, "mode").as_deref() {
+        None | Some("archive") => (
+            utils::archive_school(pool, &school_id).await?,
+            "school.archive",
+        ),
+        Some("purge") => (
+            utils::delete_school(pool, &school_id).await?,
+            "school.purge",
+        ),
+        Some(_) => return Err(ApiError::InvalidField("mode")),
+    };
+
+    if !found {
+        return Err(ApiError::NotFound);
+    }
+
+    audit::record(pool, &actor, action, "school", &school_id, None, None).await;
+
+    utils::response_empty(StatusCode::OK)
+}
+
+#[derive(Deserialize)]
+struct RolloverRequest {
+    school_id: String,
+    year: Option<i64>,
+    #[serde(default)]
+    carry_names: bool,
+    #[serde(default)]
+    promote: bool,
+    #[serde(default)]
+    carry_devices: bool,
+}
+
+pub async fn handler_rollover(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let req_data = utils::parse_req_json::<RolloverRequest>(req).await?;
+
+    let options = utils::RolloverOptions {
+        year: req_data.year.unwrap_or_else(utils::current_academic_year),
+        carry_names: req_data.carry_names,
+        promote: req_data.promote,
+        carry_devices: req_data.carry_devices,
+    };
+
+    let rollover = utils::rollover_school(pool, &req_data.school_id, &options)
+        .await
+        .map_err(|e| match e.downcast::<sqlx::Error>() {
+            Ok(e) => ApiError::from_insert(e, "classroom"),
+            Err(e) => e.into(),
+        })?;
+
+    audit::record(
+        pool,
+        &actor,
+        "school.rollover",
+        "school",
+        &req_data.school_id,
+        None,
+        Some(json!({
+            "year": options.year,
+            "archived": rollover.archived,
+            "created": rollover.created.iter().map(|c| &c.id).collect::<Vec<_>>(),
+        })),
+    )
+    .await;
+
+    utils::response_struct_json(StatusCode::OK, &rollover)
+}
+
+#[derive(Deserialize, Serialize)]
+struct MenuEntry {
+    date: String,
+    dish: String,
+    category: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ImportMenuRequest {
+    menu: Vec<MenuEntry>,
+}
+
+// CSV columns: date,dish[,category]. Fields with commas or quotes are quoted as in the export.
+fn parse_menu_csv(body: &str) -> Result<Vec<MenuEntry>> {
+    let mut menu = Vec::new();
+    for (i, record) in export::parse_csv(body)?.iter().enumerate() {
+        let mut columns = record.iter().map(|column| column.trim());
+        let date = columns.next().unwrap_or_default();
+        if (date.is_empty() && record.len() == 1) || (i == 0 && date.starts_with("date")) {
+            continue;
+        }
+        let Some(dish) = columns.next() else {
+            anyhow::bail!("Invalid menu line {}", i + 1);
+        };
+        let category = columns
+            .next()
+            .filter(|category| !category.is_empty())
+            .map(|category| category.to_string());
+        menu.push(MenuEntry {
+            date: date.to_string(),
+            dish: dish.to_string(),
+            category,
+        });
+    }
+    Ok(menu)
+}
+
+// School managed by the request. Administrators specify it with the `id` query.
+async fn get_managed_school_id(
+    pool: &Pool<Sqlite>,
+    req: &Request<hyper::body::Incoming>,
+) -> ApiResult<String> {
+    if utils::is_admin(req) {
+        let school_id = utils::get_query_param(req, "id").ok_or(ApiError::InvalidParams)?;
+        let exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM school WHERE id=$1 AND archived_at IS NULL)",
+            school_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if exists <= 0 {
+            return Err(ApiError::NotFound);
         }
+        return Ok(school_id);
+    }
+
+    let teacher = utils::get_teacher_info_from_token(pool, req).await?;
+    let school_id = sqlx::query_scalar!(
+        "SELECT school_id FROM classroom WHERE id=$1",
+        teacher.class_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(school_id)
+}
+
+pub async fn handler_import_menu(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    // The menu sets the leftover baselines of all classes in the school
+    let school_id = get_managed_school_id(pool, &req).await?;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let is_csv = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/csv"));
+
+    let menu = if is_csv {
+        let body = utils::read_body_req(req).await?;
+        parse_menu_csv(&body).map_err(|_| ApiError::InvalidField("menu"))?
+    } else {
+        utils::parse_req_json::<ImportMenuRequest>(req).await?.menu
     };
 
+    if menu.iter().any(|entry| {
+        NaiveDate::parse_from_str(&entry.date, "%Y-%m-%d").is_err() || entry.dish.is_empty()
+    }) {
+        return Err(ApiError::InvalidField("menu"));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    // Imported days replace the existing menu of the days
+    let dates: HashSet<&str> = menu.iter().map(|entry| entry.date.as_str()).collect();
+    for date in dates.iter() {
+        sqlx::query!(
+            "DELETE FROM lunch_menu WHERE school_id=$1 AND date=$2",
+            school_id,
+            date
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for entry in menu.iter() {
+        sqlx::query!(
+            "INSERT OR REPLACE INTO lunch_menu VALUES($1, $2, $3, $4)",
+            school_id,
+            entry.date,
+            entry.dish,
+            entry.category
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    audit::record(
+        pool,
+        &actor,
+        "school.menu",
+        "school",
+        &school_id,
+        None,
+        Some(json!({ "dates": dates.len(), "dishes": menu.len() })),
+    )
+    .await;
+
+    utils::response_empty(StatusCode::OK)
+}
+
+#[derive(Serialize)]
+struct MenuItem {
+    date: String,
+    dish: String,
+    category: Option<String>,
+    baseline: Option<f64>, // Expected leftovers per serving (gram)
+}
+
+pub async fn handler_get_menu(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let id = Ulid::new().to_string();
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
 
-    let result = sqlx::query!("INSERT INTO school VALUES($1, $2)", id, req_data.name)
-        .execute(pool)
-        .await;
+    // YYYY-MM
+    let month =
+        utils::get_query_param(&req, "month").unwrap_or_else(|| utils::today()[..7].to_string());
 
-    if let Err(e) = result {
-        println!("{}", e.to_string());
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
+    let school_id = sqlx::query_scalar!("SELECT school_id FROM classroom WHERE id=$1", class_id)
+        .fetch_one(pool)
+        .await?;
+
+    let menu = sqlx::query_as!(
+        MenuEntry,
+        "SELECT date, dish, category FROM lunch_menu WHERE school_id=$1 AND strftime('%Y-%m', date)=$2 ORDER BY date",
+        school_id,
+        month
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let baselines: HashMap<String, f64> =
+        utils::get_dish_baselines(pool, &school_id, &utils::today())
+            .await?
+            .into_iter()
+            .map(|baseline| (baseline.dish, baseline.grams_per_serving))
+            .collect();
+
+    let menu: Vec<MenuItem> = menu
+        .into_iter()
+        .map(|entry| MenuItem {
+            baseline: baselines.get(&entry.dish).copied(),
+            date: entry.date,
+            dish: entry.dish,
+            category: entry.category,
+        })
+        .collect();
+
+    utils::response_struct_json(StatusCode::OK, &menu)
+}
+
+#[derive(Deserialize)]
+struct ImportCalendarRequest {
+    calendar: Vec<CalendarEntry>,
+}
+
+pub async fn handler_import_calendar(
+    req: Request<hyper::body::Incoming>,
+) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    // The calendar changes the scoring of all classes in the school
+    let school_id = get_managed_school_id(pool, &req).await?;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    // Kind of the iCalendar events without CATEGORIES
+    let default_kind = match utils::get_query_param(&req, "kind") {
+        Some(kind) => DayKind::parse(&kind).ok_or(ApiError::InvalidField("kind"))?,
+        None => DayKind::Holiday,
+    };
+
+    let calendar_entries = if content_type.starts_with("text/csv") {
+        let body = utils::read_body_req(req).await?;
+        calendar::parse_csv(&body).map_err(|_| ApiError::InvalidField("calendar"))?
+    } else if content_type.starts_with("text/calendar") {
+        let body = utils::read_body_req(req).await?;
+        calendar::parse_ical(&body, default_kind).map_err(|_| ApiError::InvalidField("calendar"))?
+    } else {
+        utils::parse_req_json::<ImportCalendarRequest>(req)
+            .await?
+            .calendar
+    };
+
+    if calendar_entries
+        .iter()
+        .any(|entry| NaiveDate::parse_from_str(&entry.date, "%Y-%m-%d").is_err())
+    {
+        return Err(ApiError::InvalidField("calendar"));
     }
 
+    let mut tx = pool.begin().await?;
+
+    for entry in calendar_entries.iter() {
+        let kind = entry.kind.as_str();
+        sqlx::query!(
+            "INSERT OR REPLACE INTO school_calendar(school_id, date, kind, note) VALUES($1, $2, $3, $4)",
+            school_id,
+            entry.date,
+            kind,
+            entry.note
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    audit::record(
+        pool,
+        &actor,
+        "school.calendar",
+        "school",
+        &school_id,
+        None,
+        Some(json!({ "days": calendar_entries.len() })),
+    )
+    .await;
+
     utils::response_empty(StatusCode::OK)
 }
+
+struct CalendarRow {
+    date: String,
+    kind: String,
+    note: Option<String>,
+}
+
+pub async fn handler_get_calendar(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
+
+    // YYYY-MM
+    let month =
+        utils::get_query_param(&req, "month").unwrap_or_else(|| utils::today()[..7].to_string());
+
+    let rows = sqlx::query_as!(
+        CalendarRow,
+        "SELECT date, kind, note FROM school_calendar
+        WHERE school_id=(SELECT school_id FROM classroom WHERE id=$1) AND strftime('%Y-%m', date)=$2 ORDER BY date",
+        class_id,
+        month
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let calendar_entries: Vec<CalendarEntry> = rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(CalendarEntry {
+                kind: DayKind::parse(&row.kind)?,
+                date: row.date,
+                note: row.note,
+            })
+        })
+        .collect();
+
+    utils::response_struct_json(StatusCode::OK, &calendar_entries)
+}
+
+pub async fn handler_impact(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let class_id = utils::get_class_id_from_token(pool, &req).await?;
+
+    let (from, to) = utils::get_query_period(&req).ok_or(ApiError::InvalidField("period"))?;
+
+    let classroom = sqlx::query!(
+        "SELECT school_id, academic_year FROM classroom WHERE id=$1",
+        class_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    // Classes of the current academic year, as in the rankings
+    let class_ids = sqlx::query_scalar!(
+        "SELECT id FROM classroom WHERE school_id=$1 AND academic_year=$2 AND archived_at IS NULL",
+        classroom.school_id,
+        classroom.academic_year
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let impact = utils::calc_impact(pool, &classroom.school_id, &class_ids, &from, &to).await?;
+    utils::response_struct_json(StatusCode::OK, &impact)
+}
+
+pub async fn handler_export(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = database::get_pool().await;
+
+    let teacher = utils::get_teacher_info_from_token(&pool, &req).await?;
+
+    let format = utils::get_query_param(&req, "format").unwrap_or("ndjson".to_string());
+    let tables = match utils::get_query_param(&req, "table") {
+        Some(tables) => tables.split(',').map(|t| t.to_string()).collect(),
+        None => export::TABLES.iter().map(|t| t.to_string()).collect(),
+    };
+
+    let (format, (from, to)) = match (ExportFormat::parse(&format), utils::get_query_period(&req)) {
+        (Some(format), Some(period)) => (format, period),
+        _ => return Err(ApiError::InvalidParams),
+    };
+
+    let school_id = sqlx::query_scalar!(
+        "SELECT school_id FROM classroom WHERE id=$1",
+        teacher.class_id
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    let export_req = ExportRequest {
+        school_id,
+        from,
+        to,
+        format,
+        tables,
+    };
+
+    if let Err(e) = export_req.validate() {
+        tracing::debug!(error = %e, "invalid export request");
+        return Err(ApiError::InvalidField("table"));
+    }
+
+    let (tx, rx) = mpsc::channel(64);
+    let exporter = tokio::task::spawn(
+        async move {
+            let result = export::export(&pool, &export_req, tx).await;
+            if let Err(e) = &result {
+                tracing::error!(error = %e, "export failed");
+            }
+            result
+        }
+        .in_current_span(),
+    );
+
+    utils::response_stream(StatusCode::OK, format.content_type(), rx, exporter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn menu_csv_with_quoted_dish() {
+        let body = "date,dish,category\n2024-05-01,\"Rice, with \"\"furikake\"\"\",staple\n\n2024-05-01,Soup\n";
+        let menu = parse_menu_csv(body).unwrap();
+        let dishes: Vec<(&str, &str, Option<&str>)> = menu
+            .iter()
+            .map(|entry| {
+                (
+                    entry.date.as_str(),
+                    entry.dish.as_str(),
+                    entry.category.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            dishes,
+            [
+                ("2024-05-01", "Rice, with \"furikake\"", Some("staple")),
+                ("2024-05-01", "Soup", None),
+            ]
+        );
+    }
+}
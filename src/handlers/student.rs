@@ -1,11 +1,15 @@
-use hyper::{
-    header::{HeaderValue, SET_COOKIE},
-    Request, StatusCode,
-};
+use hyper::{Request, StatusCode};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use ulid::Ulid;
 
-use crate::{database, utils};
+use crate::{
+    achievement::{self, AwardsResponse, Badge},
+    audit, database,
+    error::ApiError,
+    streak::{self, ClassStreaks, StudentStreaks},
+    utils,
+};
 
 #[derive(Deserialize)]
 struct LoginRequest {
@@ -14,45 +18,31 @@ struct LoginRequest {
 }
 
 pub async fn handler_login(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
-    let login_data = {
-        let result = utils::parse_req_json::<LoginRequest>(req).await;
-        match result {
-            Ok(r) => r,
-            Err(e) => {
-                println!("{}", e.to_string());
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid params".to_string(),
-                );
-            }
-        }
-    };
+    login(req, false).await
+}
+
+// Login which returns the token in the body to send it as the bearer token
+pub async fn handler_login_token(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    login(req, true).await
+}
+
+async fn login(req: Request<hyper::body::Incoming>, in_body: bool) -> utils::HandlerResponse {
+    let login_data = utils::parse_req_json::<LoginRequest>(req).await?;
 
     let pool = &database::get_pool().await;
 
     // TODO: Verify class_id
     let token = Ulid::new().to_string();
-    let result = sqlx::query!(
+    sqlx::query!(
         "INSERT INTO student_token VALUES($1, $2, $3)",
         token,
         login_data.student_id,
         login_data.class_id
     )
     .execute(pool)
-    .await;
+    .await?;
 
-    if let Err(e) = result {
-        println!("{}", e.to_string());
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
-    let token_cookie = utils::create_cookie(utils::STUDENT_TOKEN.to_string(), token);
-
-    let mut response = utils::response_empty(StatusCode::OK)?;
-    response
-        .headers_mut()
-        .append(SET_COOKIE, HeaderValue::from_str(token_cookie.as_str())?);
-    Ok(response)
+    utils::response_token(utils::STUDENT_TOKEN, token, in_body)
 }
 
 #[derive(Serialize)]
@@ -65,28 +55,15 @@ pub async fn handler_exist_checklist(
 ) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let student_info = {
-        let result = utils::get_student_info_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
-        }
-    };
+    let student_info = utils::get_student_info_from_token(pool, &req).await?;
 
-    let result = sqlx::query_scalar!(
+    let exist_checklist = sqlx::query_scalar!(
         "SELECT EXISTS(SELECT * FROM checklist WHERE class_id=$1 AND student_id=$2 AND date=date('now', 'localtime'))",
         student_info.class_id, student_info.student_id
     )
     .fetch_one(pool)
-    .await;
-
-    let exist_checklist = match result {
-        Ok(v) => v > 0,
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    .await?
+        > 0;
 
     utils::response_struct_json(
         StatusCode::OK,
@@ -99,78 +76,79 @@ pub async fn handler_exist_checklist(
 pub async fn handler_checklist(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let student_info = {
-        let result = utils::get_student_info_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
-        }
-    };
-
-    let checklist = {
-        let result = utils::read_body_req(req).await;
-        match result {
-            Ok(r) => r,
-            Err(e) => {
-                println!("{}", e.to_string());
-                return utils::response_error_message(
-                    StatusCode::BAD_REQUEST,
-                    "Invalid params".to_string(),
-                );
-            }
-        }
-    };
-
-    let result = sqlx::query!(
+    let student_info = utils::get_student_info_from_token(pool, &req).await?;
+
+    let actor = audit::get_actor(pool, &req).await;
+
+    let checklist = utils::read_body_req(req).await?;
+
+    sqlx::query!(
         "INSERT INTO checklist VALUES($1, $2, $3, date('now', 'localtime'))",
         student_info.class_id,
         student_info.student_id,
         checklist
     )
     .execute(pool)
+    .await
+    .map_err(|e| ApiError::from_insert(e, "checklist"))?;
+
+    let after = serde_json::from_str(&checklist).unwrap_or(Value::String(checklist));
+    audit::record(
+        pool,
+        &actor,
+        "student.checklist",
+        "student",
+        &format!("{}/{}", student_info.class_id, student_info.student_id),
+        None,
+        Some(after),
+    )
     .await;
 
-    if let Err(e) = result {
-        eprintln!("{}", e);
-        return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
-    utils::response_empty(StatusCode::OK)
+    let achievements =
+        achievement::evaluate_or_empty(pool, &student_info.class_id, &[Badge::ChecklistStreak])
+            .await;
+    utils::response_struct_json(StatusCode::OK, &AwardsResponse { achievements })
 }
 
 #[derive(Serialize)]
 struct PointResponse {
     point: i64,
+    streaks: PointStreaks,
+}
+
+#[derive(Serialize)]
+struct PointStreaks {
+    class: ClassStreaks,
+    student: StudentStreaks,
 }
 
 pub async fn handler_point(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
     let pool = &database::get_pool().await;
 
-    let student_info = {
-        let result = utils::get_student_info_from_token(pool, &req).await;
-        match result {
-            Ok(v) => v,
-            Err(res) => return res,
-        }
-    };
+    let student_info = utils::get_student_info_from_token(pool, &req).await?;
 
-    let result = sqlx::query_scalar!(
+    // The point is 0 until something is recorded today, but the streaks continue from the past days
+    let point = sqlx::query_scalar!(
         "SELECT point FROM day_status WHERE class_id=$1 AND date=date('now', 'localtime')",
         student_info.class_id
     )
     .fetch_optional(pool)
-    .await;
+    .await?
+    .unwrap_or(0);
 
-    let point = match result {
-        Ok(v) => match v {
-            Some(_point) => _point,
-            None => return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR),
-        },
-        Err(e) => {
-            println!("{}", e.to_string());
-            return utils::response_empty(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
+    let class_streaks = streak::get_class_streaks(pool, &student_info.class_id).await?;
+
+    let student_streaks =
+        streak::get_student_streaks(pool, &student_info.class_id, student_info.student_id).await?;
 
-    utils::response_struct_json(StatusCode::OK, &PointResponse { point })
+    utils::response_struct_json(
+        StatusCode::OK,
+        &PointResponse {
+            point,
+            streaks: PointStreaks {
+                class: class_streaks,
+                student: student_streaks,
+            },
+        },
+    )
 }
@@ -0,0 +1,147 @@
+use hyper::{Request, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use ulid::Ulid;
+
+use crate::{audit, database, error::ApiError, lockout, metrics, utils};
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    email: String,
+    password: String,
+    class_id: Option<String>, // Required for administrators
+}
+
+// Create a teacher of the classroom. Administrators or the teachers of the classroom can create them,
+// since the class token is shared with the devices of the students.
+pub async fn handler_create(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    let pool = &database::get_pool().await;
+
+    let actor = audit::get_actor(pool, &req).await;
+    let teacher = if utils::is_admin(&req) {
+        None
+    } else {
+        Some(utils::get_teacher_info_from_token(pool, &req).await?)
+    };
+
+    let create_data = utils::parse_req_json::<CreateRequest>(req).await?;
+    utils::check_password_strength(&create_data.password)?;
+
+    let class_id = match (teacher, create_data.class_id) {
+        (None, Some(class_id)) => class_id,
+        (None, None) => return Err(ApiError::InvalidField("class_id")),
+        (Some(teacher), Some(class_id)) if class_id != teacher.class_id => {
+            return Err(ApiError::Forbidden)
+        }
+        (Some(teacher), _) => teacher.class_id,
+    };
+
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM classroom WHERE id=$1 AND archived_at IS NULL)",
+        class_id
+    )
+    .fetch_one(pool)
+    .await?;
+    if exists <= 0 {
+        return Err(ApiError::InvalidField("class_id"));
+    }
+
+    let hash = utils::compute_password_hash(create_data.password);
+    let id = Ulid::new().to_string();
+
+    sqlx::query!(
+        "INSERT INTO teacher VALUES($1, $2, $3, $4)",
+        id,
+        class_id,
+        create_data.email,
+        hash
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| ApiError::from_insert(e, "email"))?;
+
+    audit::record(
+        pool,
+        &actor,
+        "teacher.create",
+        "teacher",
+        &id,
+        None,
+        Some(json!({ "class_id": class_id, "email": create_data.email })),
+    )
+    .await;
+
+    utils::response_empty(StatusCode::OK)
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    email: String,
+    password: String,
+}
+
+pub async fn handler_login(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    login(req, false).await
+}
+
+// Login which returns the token in the body to send it as the bearer token
+pub async fn handler_login_token(req: Request<hyper::body::Incoming>) -> utils::HandlerResponse {
+    login(req, true).await
+}
+
+async fn login(req: Request<hyper::body::Incoming>, in_body: bool) -> utils::HandlerResponse {
+    let ip_key = lockout::ip_key(utils::get_client_ip(&req));
+
+    let login_data = utils::parse_req_json::<LoginRequest>(req).await?;
+
+    let pool = &database::get_pool().await;
+
+    let teacher_key = lockout::account_key("teacher", &login_data.email);
+    lockout::check(pool, &[ip_key.clone(), teacher_key.clone()]).await?;
+
+    let Some(teacher) = sqlx::query!(
+        r#"SELECT teacher.id AS "id!", teacher.password_hash AS "password_hash!" FROM teacher
+        JOIN classroom ON classroom.id = teacher.class_id
+        WHERE teacher.email=$1 AND classroom.archived_at IS NULL"#,
+        login_data.email
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        metrics::record_login_failure("teacher");
+        lockout::record_failure(pool, &[ip_key]).await?;
+        return Err(ApiError::InvalidField("email"));
+    };
+
+    // Check password
+    if !utils::verify_password(login_data.password.clone(), teacher.password_hash.clone())? {
+        metrics::record_login_failure("teacher");
+        lockout::record_failure(pool, &[ip_key, teacher_key]).await?;
+        return Err(ApiError::IncorrectPassword);
+    }
+    lockout::reset(pool, &teacher_key).await?;
+
+    // Upgrade the hash made with the older params while we know the password
+    if utils::needs_rehash(&teacher.password_hash) {
+        let hash = utils::compute_password_hash(login_data.password);
+        sqlx::query!(
+            "UPDATE teacher SET password_hash=$1 WHERE id=$2 AND password_hash=$3",
+            hash,
+            teacher.id,
+            teacher.password_hash
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    let token = Ulid::new().to_string();
+    sqlx::query!(
+        "INSERT INTO teacher_token VALUES($1, $2)",
+        token,
+        teacher.id
+    )
+    .execute(pool)
+    .await?;
+
+    utils::response_token(utils::TEACHER_TOKEN, token, in_body)
+}
@@ -0,0 +1,49 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use sqlx::{Pool, Sqlite};
+
+use crate::database::MIGRATOR;
+
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+pub fn mark_started() {
+    Lazy::force(&STARTED);
+}
+
+pub fn uptime() -> Duration {
+    STARTED.elapsed()
+}
+
+// The server is shutting down. It still serves the requests, but the load balancers should stop sending them.
+pub fn start_draining() {
+    DRAINING.store(true, Ordering::Relaxed);
+}
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+pub async fn check_database(pool: &Pool<Sqlite>) -> Result<()> {
+    sqlx::query_scalar!("SELECT 1").fetch_one(pool).await?;
+    Ok(())
+}
+
+// All the migrations of the binary are applied successfully
+pub async fn check_migrations(pool: &Pool<Sqlite>) -> Result<bool> {
+    let applied: HashSet<i64> = sqlx::query_scalar!(
+        r#"SELECT version AS "version!" FROM _sqlx_migrations WHERE success=1"#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+    Ok(MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .all(|m| applied.contains(&m.version)))
+}
@@ -0,0 +1,92 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+
+use crate::config::CONFIG;
+use crate::error::{ApiError, ApiResult};
+
+// Failures older than the longest lockout are forgotten
+fn failure_window() -> i64 {
+    CONFIG.login_lockout_max_secs as i64
+}
+
+pub fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+// `kind` is the kind of the account like "class" or "teacher"
+pub fn account_key(kind: &str, id: &str) -> String {
+    format!("{}:{}", kind, id)
+}
+
+fn max_failures(key: &str) -> i64 {
+    // Many users share the address of the school, so the address allows more failures
+    if key.starts_with("ip:") {
+        CONFIG.login_max_failures_ip
+    } else {
+        CONFIG.login_max_failures
+    }
+}
+
+// Seconds of the lockout after the failures. Doubles with every failure over the limit.
+fn lockout_secs(failures: i64, max_failures: i64) -> Option<i64> {
+    if failures < max_failures {
+        return None;
+    }
+    let exp = (failures - max_failures).min(30) as u32;
+    let secs = (CONFIG.login_lockout_secs as i64).saturating_mul(1 << exp);
+    Some(secs.min(CONFIG.login_lockout_max_secs as i64))
+}
+
+// Fail with 429 if any of the keys is locked out
+pub async fn check(pool: &Pool<Sqlite>, keys: &[String]) -> ApiResult<()> {
+    let now = Utc::now().timestamp();
+    for key in keys {
+        let locked_until =
+            sqlx::query_scalar!("SELECT locked_until FROM login_attempt WHERE key=$1", key)
+                .fetch_optional(pool)
+                .await?
+                .flatten();
+        if let Some(locked_until) = locked_until.filter(|t| *t > now) {
+            return Err(ApiError::TooManyRequests((locked_until - now) as u64));
+        }
+    }
+    Ok(())
+}
+
+pub async fn record_failure(pool: &Pool<Sqlite>, keys: &[String]) -> Result<()> {
+    let now = Utc::now().timestamp();
+    let expired = now - failure_window();
+    for key in keys {
+        let failures = sqlx::query_scalar!(
+            "SELECT failures FROM login_attempt WHERE key=$1 AND last_failed_at > $2",
+            key,
+            expired
+        )
+        .fetch_optional(pool)
+        .await?
+        .unwrap_or(0)
+            + 1;
+        let locked_until = lockout_secs(failures, max_failures(key)).map(|secs| now + secs);
+        sqlx::query!(
+            "REPLACE INTO login_attempt(key, failures, last_failed_at, locked_until) VALUES($1, $2, $3, $4)",
+            key,
+            failures,
+            now,
+            locked_until
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+// Forget the failures of the account after the successful login
+pub async fn reset(pool: &Pool<Sqlite>, key: &str) -> Result<()> {
+    sqlx::query!("DELETE FROM login_attempt WHERE key=$1", key)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
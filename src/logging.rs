@@ -0,0 +1,26 @@
+use std::io::IsTerminal;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogFormat, CONFIG};
+
+// Logs go to stderr, since the CLI writes the exports to stdout.
+// RUST_LOG overrides LOG_LEVEL for debugging.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&CONFIG.log_level))
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match CONFIG.log_format {
+        LogFormat::Text => builder.init(),
+        // Fields of the request span like request_id are in "span"
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
@@ -3,17 +3,69 @@ use config::CONFIG;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
+use router::ClientAddr;
 use std::env;
 use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::oneshot;
 
+mod achievement;
+mod audit;
+mod calendar;
+mod challenge;
+mod cli;
 mod config;
 mod database;
+mod error;
+mod export;
 mod handlers;
+mod health;
+mod lockout;
+mod logging;
+mod metrics;
+mod middleware;
+mod router;
+mod streak;
 mod utils;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    logging::init();
+    match args.first().map(|arg| arg.as_str()) {
+        None | Some("serve") => serve().await,
+        Some(_) => cli::run(&args).await,
+    }
+}
+
+// SIGTERM from the container runtimes, or Ctrl+C
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+async fn serve() -> Result<()> {
+    health::mark_started();
+    calendar::check_config()?;
     database::init().await;
 
     let addr: SocketAddr = CONFIG
@@ -23,25 +75,60 @@ async fn main() -> Result<()> {
 
     // We create a TcpListener and bind it to 127.0.0.1:3000
     let listener = TcpListener::bind(addr).await?;
+    tracing::info!(%addr, version = env!("CARGO_PKG_VERSION"), "listening");
+
+    // On the signal, /readyz fails for SHUTDOWN_DRAIN_SECS while serving the requests,
+    // so that the load balancers stop sending new ones before we stop accepting them
+    let (stop_tx, mut stop_rx) = oneshot::channel();
+    tokio::task::spawn(async move {
+        shutdown_signal().await;
+        health::start_draining();
+        tracing::info!(
+            drain_secs = CONFIG.shutdown_drain_secs,
+            "shutting down, draining"
+        );
+        tokio::time::sleep(Duration::from_secs(CONFIG.shutdown_drain_secs)).await;
+        let _ = stop_tx.send(());
+    });
+    let graceful = GracefulShutdown::new();
 
     // We start a loop to continuously accept incoming connections
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut stop_rx => break,
+        };
 
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
         // `hyper::rt` IO traits.
         let io = TokioIo::new(stream);
 
+        // `service_fn` converts our function in a `Service`
+        let connection = http1::Builder::new().serve_connection(
+            io,
+            service_fn(move |mut req| {
+                req.extensions_mut().insert(ClientAddr(peer_addr));
+                handlers::route(req)
+            }),
+        );
+        // Idle keep-alive connections are closed at the shutdown
+        let connection = graceful.watch(connection);
+
         // Spawn a tokio task to serve multiple connections concurrently
         tokio::task::spawn(async move {
-            // Finally, we bind the incoming connection to our `hello` service
-            if let Err(err) = http1::Builder::new()
-                // `service_fn` converts our function in a `Service`
-                .serve_connection(io, service_fn(handlers::route))
-                .await
-            {
-                eprintln!("Error serving connection: {:?}", err);
+            if let Err(err) = connection.await {
+                tracing::warn!(error = ?err, %peer_addr, "error serving connection");
             }
         });
     }
+
+    // Wait for the requests in progress
+    drop(listener);
+    tokio::select! {
+        _ = graceful.shutdown() => tracing::info!("all connections are closed"),
+        _ = tokio::time::sleep(Duration::from_secs(CONFIG.shutdown_timeout_secs)) => {
+            tracing::warn!("timed out waiting for the connections to close");
+        }
+    }
+    Ok(())
 }
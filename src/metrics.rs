@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::Result;
+use hyper::Method;
+use once_cell::sync::Lazy;
+use sqlx::{Pool, Sqlite};
+
+// Upper bounds of the latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()], // Not cumulative
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| value <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Counters of the process. They are reset when the server restarts, which Prometheus can handle.
+#[derive(Default)]
+struct Metrics {
+    requests: BTreeMap<(&'static str, &'static str, u16), u64>, // method, route, status
+    latencies: BTreeMap<(&'static str, &'static str), Histogram>, // method, route
+    sensor_readings: BTreeMap<String, u64>,                     // class ID
+    validation_rejects: BTreeMap<(&'static str, &'static str), u64>, // code, field
+    login_failures: BTreeMap<&'static str, u64>,                // account kind
+}
+
+static METRICS: Lazy<Mutex<Metrics>> = Lazy::new(Mutex::default);
+
+fn metrics() -> MutexGuard<'static, Metrics> {
+    METRICS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Unknown methods are put together, so that the clients can't add the labels freely
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        _ => "OTHER",
+    }
+}
+
+// `route` is the pattern of the matched route like "/classroom/{id}/history"
+pub fn record_request(method: &Method, route: &'static str, status: u16, elapsed: Duration) {
+    let method = method_label(method);
+    let mut metrics = metrics();
+    *metrics.requests.entry((method, route, status)).or_default() += 1;
+    metrics
+        .latencies
+        .entry((method, route))
+        .or_default()
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn record_sensor_reading(class_id: &str) {
+    let mut metrics = metrics();
+    match metrics.sensor_readings.get_mut(class_id) {
+        Some(count) => *count += 1,
+        None => {
+            metrics.sensor_readings.insert(class_id.to_string(), 1);
+        }
+    }
+}
+
+pub fn record_validation_reject(code: &'static str, field: Option<&'static str>) {
+    *metrics()
+        .validation_rejects
+        .entry((code, field.unwrap_or("")))
+        .or_default() += 1;
+}
+
+// `kind` is "class" or "teacher"
+pub fn record_login_failure(kind: &'static str) {
+    *metrics().login_failures.entry(kind).or_default() += 1;
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn render_counters(out: &mut String) {
+    let metrics = metrics();
+
+    header(
+        out,
+        "ecowatch_http_requests_total",
+        "counter",
+        "HTTP requests by route and status.",
+    );
+    for ((method, route, status), count) in &metrics.requests {
+        let _ = writeln!(
+            out,
+            "ecowatch_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+            method,
+            escape(route),
+            status,
+            count
+        );
+    }
+
+    header(
+        out,
+        "ecowatch_http_request_duration_seconds",
+        "histogram",
+        "Latency of the HTTP requests by route.",
+    );
+    for ((method, route), histogram) in &metrics.latencies {
+        let labels = format!("method=\"{}\",route=\"{}\"", method, escape(route));
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "ecowatch_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "ecowatch_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "ecowatch_http_request_duration_seconds_sum{{{}}} {}",
+            labels, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "ecowatch_http_request_duration_seconds_count{{{}}} {}",
+            labels, histogram.count
+        );
+    }
+
+    header(
+        out,
+        "ecowatch_sensor_readings_total",
+        "counter",
+        "Sensor readings ingested by class.",
+    );
+    for (class_id, count) in &metrics.sensor_readings {
+        let _ = writeln!(
+            out,
+            "ecowatch_sensor_readings_total{{class_id=\"{}\"}} {}",
+            escape(class_id),
+            count
+        );
+    }
+
+    header(
+        out,
+        "ecowatch_validation_rejects_total",
+        "counter",
+        "Requests rejected with 400 by error code and field.",
+    );
+    for ((code, field), count) in &metrics.validation_rejects {
+        let _ = writeln!(
+            out,
+            "ecowatch_validation_rejects_total{{code=\"{}\",field=\"{}\"}} {}",
+            code, field, count
+        );
+    }
+
+    header(
+        out,
+        "ecowatch_login_failures_total",
+        "counter",
+        "Failed password checks by account kind.",
+    );
+    for (kind, count) in &metrics.login_failures {
+        let _ = writeln!(
+            out,
+            "ecowatch_login_failures_total{{kind=\"{}\"}} {}",
+            kind, count
+        );
+    }
+}
+
+// Text exposition format of Prometheus. The gauges are read at the scrape.
+pub async fn render(pool: &Pool<Sqlite>) -> Result<String> {
+    let mut out = String::new();
+    render_counters(&mut out);
+
+    let size = pool.size();
+    let idle = pool.num_idle() as u32;
+    header(
+        &mut out,
+        "ecowatch_db_pool_connections",
+        "gauge",
+        "Connections of the database pool by state.",
+    );
+    let _ = writeln!(
+        out,
+        "ecowatch_db_pool_connections{{state=\"in_use\"}} {}",
+        size.saturating_sub(idle)
+    );
+    let _ = writeln!(
+        out,
+        "ecowatch_db_pool_connections{{state=\"idle\"}} {}",
+        idle
+    );
+    header(
+        &mut out,
+        "ecowatch_db_pool_max_connections",
+        "gauge",
+        "Maximum connections of the database pool.",
+    );
+    let _ = writeln!(
+        out,
+        "ecowatch_db_pool_max_connections {}",
+        pool.options().get_max_connections()
+    );
+
+    let points = sqlx::query!(
+        r#"SELECT classroom.id, classroom.school_id, COALESCE(day_status.point, 0) AS "point!: i64"
+        FROM classroom LEFT JOIN day_status ON day_status.class_id=classroom.id AND day_status.date=date('now', 'localtime')
+        WHERE classroom.archived_at IS NULL ORDER BY classroom.id"#
+    )
+    .fetch_all(pool)
+    .await?;
+    header(
+        &mut out,
+        "ecowatch_class_points",
+        "gauge",
+        "Points of the classes today.",
+    );
+    for row in points {
+        let _ = writeln!(
+            out,
+            "ecowatch_class_points{{class_id=\"{}\",school_id=\"{}\"}} {}",
+            escape(&row.id),
+            escape(&row.school_id),
+            row.point
+        );
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use futures_util::future::BoxFuture;
+use hyper::header::{self, HeaderValue};
+use hyper::Method;
+use tracing::Instrument;
+use ulid::Ulid;
+
+use crate::config::CONFIG;
+use crate::database;
+use crate::error::ApiError;
+use crate::metrics;
+use crate::router::{MatchedRoute, Middleware, Next, Req};
+use crate::utils::{self, HandlerResponse};
+
+// Run the request in a span with the request ID, so that the logs of the handlers carry it,
+// and write the access log. The ID is sent back in X-Request-Id.
+pub struct Logger;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Probes of the orchestrators, which would flood the access logs
+const PROBE_PATHS: [&str; 2] = ["/healthz", "/readyz"];
+
+// IDs which the proxies set are kept if they are reasonable
+fn request_id(req: &Req) -> String {
+    let forwarded = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| CONFIG.trust_proxy && !id.is_empty() && id.len() <= 64);
+    match forwarded {
+        Some(id) => id.to_string(),
+        None => Ulid::new().to_string(),
+    }
+}
+
+impl Middleware for Logger {
+    fn handle<'a>(&'a self, req: Req, next: Next<'a>) -> BoxFuture<'a, HandlerResponse> {
+        let id = request_id(&req);
+        let probe = PROBE_PATHS.contains(&req.uri().path());
+        let span = tracing::info_span!(
+            "request",
+            request_id = %id,
+            method = %req.method(),
+            path = req.uri().path(),
+        );
+        Box::pin(
+            async move {
+                let start = Instant::now();
+                let mut response = next.run(req).await.unwrap_or_else(ApiError::into_response);
+                let status = response.status().as_u16();
+                let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+                if response.status().is_server_error() && !probe {
+                    tracing::error!(status, latency_ms, "request failed");
+                } else if probe {
+                    tracing::debug!(status, latency_ms, "request");
+                } else {
+                    tracing::info!(status, latency_ms, "request");
+                }
+                if let Ok(id) = HeaderValue::from_str(&id) {
+                    response.headers_mut().insert(REQUEST_ID_HEADER, id);
+                }
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+// Count the requests and their latency by the matched route.
+// Unknown paths are put together, so that they don't make the labels.
+pub struct RequestMetrics;
+
+impl Middleware for RequestMetrics {
+    fn handle<'a>(&'a self, req: Req, next: Next<'a>) -> BoxFuture<'a, HandlerResponse> {
+        Box::pin(async move {
+            let start = Instant::now();
+            let method = req.method().clone();
+            let response = next.run(req).await.unwrap_or_else(ApiError::into_response);
+            let route = response
+                .extensions()
+                .get::<MatchedRoute>()
+                .map_or("unmatched", |route| route.0);
+            metrics::record_request(&method, route, response.status().as_u16(), start.elapsed());
+            Ok(response)
+        })
+    }
+}
+
+// Allow the cross-origin requests with the credentials from the origins in CORS_ORIGINS
+pub struct Cors {
+    origins: Vec<String>,
+}
+
+// Headers which the frontends send in the cross-origin requests
+const CORS_ALLOW_HEADERS: &str = "authorization, content-type, x-csrf-token";
+const CORS_MAX_AGE: &str = "600";
+
+impl Cors {
+    pub fn from_config() -> Self {
+        Self {
+            origins: CONFIG.cors_origin_list(),
+        }
+    }
+
+    fn is_allowed(&self, origin: &HeaderValue) -> bool {
+        self.origins
+            .iter()
+            .any(|o| o.as_bytes() == origin.as_bytes())
+    }
+}
+
+impl Middleware for Cors {
+    fn handle<'a>(&'a self, req: Req, next: Next<'a>) -> BoxFuture<'a, HandlerResponse> {
+        Box::pin(async move {
+            let origin = req
+                .headers()
+                .get(header::ORIGIN)
+                .filter(|origin| self.is_allowed(origin))
+                .cloned();
+            let preflight = req.method() == Method::OPTIONS
+                && req
+                    .headers()
+                    .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+            // Errors need the headers too, so that the browsers can read them
+            let mut response = next.run(req).await.unwrap_or_else(ApiError::into_response);
+            let headers = response.headers_mut();
+
+            // The responses differ by the origin, so the caches must not share them
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            if preflight {
+                headers.append(
+                    header::VARY,
+                    HeaderValue::from_static(
+                        "Access-Control-Request-Method, Access-Control-Request-Headers",
+                    ),
+                );
+            }
+
+            let Some(origin) = origin else {
+                return Ok(response);
+            };
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+            if preflight {
+                // The router answers OPTIONS with the methods of the path
+                if let Some(allow) = headers.get(header::ALLOW).cloned() {
+                    headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, allow);
+                }
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_HEADERS,
+                    HeaderValue::from_static(CORS_ALLOW_HEADERS),
+                );
+                headers.insert(
+                    header::ACCESS_CONTROL_MAX_AGE,
+                    HeaderValue::from_static(CORS_MAX_AGE),
+                );
+            } else {
+                headers.insert(
+                    header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                    HeaderValue::from_static("retry-after, x-request-id"),
+                );
+            }
+            Ok(response)
+        })
+    }
+}
+
+// Reject the state-changing requests with the cookies which don't come from our frontends.
+// They need the CSRF token of the login in X-CSRF-Token, and the trusted Origin if any.
+// Requests with the bearer token are out of the target, because browsers never add it by themselves.
+pub struct Csrf {
+    origins: Vec<String>,      // Trusted cross-site origins
+    exempt: Vec<&'static str>, // Paths which don't need the CSRF token like the logins
+}
+
+impl Csrf {
+    pub fn from_config(exempt: Vec<&'static str>) -> Self {
+        Self {
+            origins: CONFIG.cors_origin_list(),
+            exempt,
+        }
+    }
+
+    fn is_trusted_origin(&self, req: &Req) -> bool {
+        let headers = req.headers();
+        let Some(origin) = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok()) else {
+            // Old browsers don't send Origin, but they send Sec-Fetch-Site if they can tell
+            return headers
+                .get("sec-fetch-site")
+                .is_none_or(|site| site.as_bytes() != b"cross-site");
+        };
+        if self.origins.iter().any(|o| o == origin) {
+            return true;
+        }
+        // Same origin
+        let host = origin
+            .strip_prefix("https://")
+            .or_else(|| origin.strip_prefix("http://"));
+        host.is_some() && host == headers.get(header::HOST).and_then(|v| v.to_str().ok())
+    }
+
+    fn has_valid_token(&self, req: &Req) -> bool {
+        let header = req.headers().get(utils::CSRF_HEADER);
+        match (
+            header,
+            utils::get_cookie(req, utils::CSRF_TOKEN.to_string()),
+        ) {
+            (Some(header), Some(cookie)) => utils::secure_eq(header.as_bytes(), cookie.as_bytes()),
+            _ => false,
+        }
+    }
+}
+
+impl Middleware for Csrf {
+    fn handle<'a>(&'a self, req: Req, next: Next<'a>) -> BoxFuture<'a, HandlerResponse> {
+        Box::pin(async move {
+            let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+            let with_cookie =
+                utils::has_token_cookie(&req) && utils::get_bearer_token(&req).is_none();
+            if safe || !with_cookie {
+                return next.run(req).await;
+            }
+            if !self.is_trusted_origin(&req) {
+                return Err(ApiError::CsrfFailed);
+            }
+            if !self.exempt.contains(&req.uri().path()) && !self.has_valid_token(&req) {
+                return Err(ApiError::CsrfFailed);
+            }
+            next.run(req).await
+        })
+    }
+}
+
+// Reject the bodies larger than MAX_BODY_BYTES before reading them.
+// Chunked bodies are limited while reading them.
+pub struct BodyLimit;
+
+impl Middleware for BodyLimit {
+    fn handle<'a>(&'a self, req: Req, next: Next<'a>) -> BoxFuture<'a, HandlerResponse> {
+        Box::pin(async move {
+            let length = req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<usize>().ok());
+            if length.is_some_and(|length| length > CONFIG.max_body_bytes) {
+                return Err(ApiError::PayloadTooLarge);
+            }
+            next.run(req).await
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Limit the requests per minute of each classroom (of a valid class token), or of each address without it.
+// The requests can burst up to the limit.
+pub struct RateLimit {
+    per_minute: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+// Forget the full buckets, or else the least recently used one, when there are this many keys
+const RATE_LIMIT_MAX_KEYS: usize = 10000;
+
+impl RateLimit {
+    pub fn per_minute(per_minute: u32) -> Self {
+        Self {
+            per_minute: per_minute.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Take a token of the key. Returns the seconds to wait if there are no tokens.
+    fn take(&self, key: String) -> Result<(), u64> {
+        let now = Instant::now();
+        let rate = self.per_minute / 60.0;
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if !buckets.contains_key(&key) && buckets.len() >= RATE_LIMIT_MAX_KEYS {
+            let per_minute = self.per_minute;
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < per_minute
+            });
+            if buckets.len() >= RATE_LIMIT_MAX_KEYS {
+                let oldest = buckets
+                    .iter()
+                    .min_by_key(|(_, b)| b.updated)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    buckets.remove(&oldest);
+                }
+            }
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.per_minute,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate)
+            .min(self.per_minute);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(((1.0 - bucket.tokens) / rate).ceil() as u64);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle<'a>(&'a self, req: Req, next: Next<'a>) -> BoxFuture<'a, HandlerResponse> {
+        Box::pin(async move {
+            // Random tokens don't get their own buckets
+            let pool = database::get_pool().await;
+            let key = match utils::get_class_id_from_token(&pool, &req).await {
+                Ok(class_id) => format!("class:{}", class_id),
+                Err(_) => format!("ip:{}", utils::get_client_ip(&req)),
+            };
+            self.take(key).map_err(ApiError::TooManyRequests)?;
+            next.run(req).await
+        })
+    }
+}
+
+// Only administrators with ADMIN_TOKEN can call the route
+pub struct RequireAdmin;
+
+impl Middleware for RequireAdmin {
+    fn handle<'a>(&'a self, req: Req, next: Next<'a>) -> BoxFuture<'a, HandlerResponse> {
+        Box::pin(async move {
+            if !utils::is_admin(&req) {
+                return Err(ApiError::Unauthorized);
+            }
+            next.run(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_takes_tokens_of_key() {
+        let limit = RateLimit::per_minute(2);
+        assert!(limit.take("a".to_string()).is_ok());
+        assert!(limit.take("a".to_string()).is_ok());
+        assert!(limit.take("a".to_string()).is_err());
+        assert!(limit.take("b".to_string()).is_ok());
+    }
+
+    #[test]
+    fn rate_limit_keys_are_bounded() {
+        let limit = RateLimit::per_minute(10);
+        for i in 0..RATE_LIMIT_MAX_KEYS + 100 {
+            assert!(limit.take(i.to_string()).is_ok());
+        }
+        let buckets = limit.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), RATE_LIMIT_MAX_KEYS);
+        assert!(buckets.contains_key(&(RATE_LIMIT_MAX_KEYS + 99).to_string()));
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Method, Request, StatusCode};
+
+use crate::error::ApiError;
+use crate::utils::{self, HandlerResponse};
+
+pub type Req = Request<hyper::body::Incoming>;
+
+type Handler = Box<dyn Fn(Req) -> BoxFuture<'static, HandlerResponse> + Send + Sync>;
+type Endpoint<'a> = dyn Fn(Req) -> BoxFuture<'a, HandlerResponse> + Send + Sync + 'a;
+
+// Middlewares wrap the handlers. They can inspect and answer the request by themselves,
+// or pass it to the rest of the chain with `next.run(req)`.
+pub trait Middleware: Send + Sync {
+    fn handle<'a>(&'a self, req: Req, next: Next<'a>) -> BoxFuture<'a, HandlerResponse>;
+}
+
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    endpoint: &'a Endpoint<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, req: Req) -> BoxFuture<'a, HandlerResponse> {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.handle(
+                req,
+                Next {
+                    middlewares: rest,
+                    endpoint: self.endpoint,
+                },
+            ),
+            None => (self.endpoint)(req),
+        }
+    }
+}
+
+// Address of the peer, which the server sets to the requests
+#[derive(Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
+// Pattern of the route which answered the request. The router sets it to the responses.
+#[derive(Clone, Copy)]
+pub struct MatchedRoute(pub &'static str);
+
+// Parameters captured by `{name}` segments of the matched route
+#[derive(Clone, Default)]
+pub struct PathParams(HashMap<&'static str, String>);
+
+impl PathParams {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|v| v.as_str())
+    }
+}
+
+enum Segment {
+    Static(&'static str),
+    Param(&'static str),
+}
+
+struct Route {
+    method: Method,
+    pattern: &'static str,
+    segments: Vec<Segment>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    handler: Handler,
+}
+
+impl Route {
+    fn matches(&self, path: &[&str]) -> Option<PathParams> {
+        if self.segments.len() != path.len() {
+            return None;
+        }
+        let mut params = PathParams::default();
+        for (segment, value) in self.segments.iter().zip(path) {
+            match segment {
+                Segment::Static(s) if s == value => {}
+                Segment::Param(name) if !value.is_empty() => {
+                    let value = percent_encoding::percent_decode_str(value)
+                        .decode_utf8()
+                        .ok()?;
+                    params.0.insert(name, value.into_owned());
+                }
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+fn parse_pattern(pattern: &'static str) -> Vec<Segment> {
+    pattern
+        .trim_start_matches('/')
+        .split('/')
+        .map(
+            |s| match s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => Segment::Param(name),
+                None => Segment::Static(s),
+            },
+        )
+        .collect()
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Middlewares run for all the requests in the order they are added
+    pub fn layer<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn route<F, Fut>(self, method: Method, pattern: &'static str, handler: F) -> Self
+    where
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResponse> + Send + 'static,
+    {
+        self.route_with(method, pattern, Vec::new(), handler)
+    }
+
+    // The route with its own middlewares, which run after the global ones
+    pub fn route_with<F, Fut>(
+        mut self,
+        method: Method,
+        pattern: &'static str,
+        middlewares: Vec<Arc<dyn Middleware>>,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResponse> + Send + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            pattern,
+            segments: parse_pattern(pattern),
+            middlewares,
+            handler: Box::new(move |req| Box::pin(handler(req))),
+        });
+        self
+    }
+
+    pub async fn handle(&self, req: Req) -> HandlerResponse {
+        let endpoint = |req| -> BoxFuture<'_, HandlerResponse> { Box::pin(self.dispatch(req)) };
+        Next {
+            middlewares: &self.middlewares,
+            endpoint: &endpoint,
+        }
+        .run(req)
+        .await
+    }
+
+    async fn dispatch(&self, mut req: Req) -> HandlerResponse {
+        let path = req.uri().path().to_string();
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(params) = route.matches(&segments) else {
+                continue;
+            };
+            if route.method != req.method() {
+                if !allowed.contains(&route.method.as_str()) {
+                    allowed.push(route.method.as_str());
+                }
+                continue;
+            }
+            req.extensions_mut().insert(params);
+            let endpoint = |req| -> BoxFuture<'_, HandlerResponse> { (route.handler)(req) };
+            let mut response = Next {
+                middlewares: &route.middlewares,
+                endpoint: &endpoint,
+            }
+            .run(req)
+            .await
+            .unwrap_or_else(ApiError::into_response);
+            response
+                .extensions_mut()
+                .insert(MatchedRoute(route.pattern));
+            return Ok(response);
+        }
+
+        // The path exists, but not for the method
+        if allowed.is_empty() {
+            return Err(ApiError::NotFound);
+        }
+        allowed.push(Method::OPTIONS.as_str());
+        let allow = HeaderValue::from_str(&allowed.join(", "))?;
+
+        let mut response = if req.method() == Method::OPTIONS {
+            utils::response_empty(StatusCode::NO_CONTENT)?
+        } else {
+            ApiError::MethodNotAllowed.into_response()
+        };
+        response.headers_mut().insert(ALLOW, allow);
+        Ok(response)
+    }
+}
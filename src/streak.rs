@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use chrono::{Local, NaiveDate};
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+
+use crate::{calendar, utils};
+
+// Consecutive school days which satisfy the condition. Non-school days don't break the streaks.
+#[derive(Serialize)]
+pub struct Streak {
+    pub current: i64,
+    pub best: i64,
+}
+
+#[derive(Serialize)]
+pub struct ClassStreaks {
+    pub points: Streak,    // Days with positive points
+    pub checklist: Streak, // Days which all attendees submitted the checklist
+    pub leftovers: Streak, // Days with leftovers under the baseline
+}
+
+#[derive(Serialize)]
+pub struct StudentStreaks {
+    pub checklist: Streak, // Days which the student submitted the checklist
+}
+
+// Days of the class with positive points
+pub async fn positive_point_days(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    from: &str,
+) -> Result<HashSet<String>> {
+    let dates = sqlx::query_scalar!(
+        "SELECT date FROM day_status WHERE class_id=$1 AND date >= $2 AND point > 0",
+        class_id,
+        from
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(dates.into_iter().collect())
+}
+
+// Days of the class which all attendees submitted the checklist
+pub async fn full_checklist_days(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    from: &str,
+) -> Result<HashSet<String>> {
+    let dates = sqlx::query_scalar!(
+        "SELECT day_status.date FROM day_status
+        JOIN checklist ON checklist.class_id=day_status.class_id AND checklist.date=day_status.date
+        WHERE day_status.class_id=$1 AND day_status.date >= $2 AND day_status.attend > 0
+        GROUP BY day_status.date HAVING COUNT(*) >= day_status.attend",
+        class_id,
+        from
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(dates.into_iter().collect())
+}
+
+// Days of the class which the leftovers were under the baseline
+pub async fn under_baseline_days(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    from: &str,
+) -> Result<HashSet<String>> {
+    let day_status_list = sqlx::query_as!(
+        utils::DayStatus,
+        "SELECT * FROM day_status WHERE class_id=$1 AND date >= $2 AND leftovers IS NOT NULL",
+        class_id,
+        from
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut dates = HashSet::new();
+    let Some(to) = day_status_list
+        .iter()
+        .map(|status| status.date.clone())
+        .max()
+    else {
+        return Ok(dates);
+    };
+    let school_id = sqlx::query_scalar!("SELECT school_id FROM classroom WHERE id=$1", class_id)
+        .fetch_one(pool)
+        .await?;
+    let baselines = utils::LeftoversBaselines::load(pool, &school_id, from, &to).await?;
+
+    for status in day_status_list {
+        let servings = status.servings.or(status.attend).unwrap_or(0);
+        let leftovers = status.leftovers.unwrap_or(0);
+        if servings > 0 {
+            let baseline = baselines.get(&status.date);
+            if (leftovers as f64 / servings as f64) < baseline {
+                dates.insert(status.date);
+            }
+        }
+    }
+    Ok(dates)
+}
+
+// Days which the student submitted the checklist
+async fn student_checklist_days(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    student_id: i64,
+    from: &str,
+) -> Result<HashSet<String>> {
+    let student_id = student_id.to_string();
+    let dates = sqlx::query_scalar!(
+        "SELECT date FROM checklist WHERE class_id=$1 AND student_id=$2 AND date >= $3",
+        class_id,
+        student_id,
+        from
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(dates.into_iter().collect())
+}
+
+// Streak until the latest school day. Today doesn't break the streak until it ends.
+pub fn current_streak(school_days: &[NaiveDate], days: &HashSet<String>, today: NaiveDate) -> i64 {
+    school_days
+        .iter()
+        .rev()
+        .skip_while(|date| **date == today && !days.contains(&date.to_string()))
+        .take_while(|date| days.contains(&date.to_string()))
+        .count() as i64
+}
+
+pub fn best_streak(school_days: &[NaiveDate], days: &HashSet<String>) -> i64 {
+    let mut best = 0;
+    let mut run = 0;
+    for date in school_days {
+        if days.contains(&date.to_string()) {
+            run += 1;
+            best = std::cmp::max(best, run);
+        } else {
+            run = 0;
+        }
+    }
+    best
+}
+
+fn calc_streak(school_days: &[NaiveDate], days: &HashSet<String>, today: NaiveDate) -> Streak {
+    Streak {
+        current: current_streak(school_days, days, today),
+        best: best_streak(school_days, days),
+    }
+}
+
+// School days of the academic year of the class until today
+async fn class_school_days(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    today: NaiveDate,
+) -> Result<(NaiveDate, Vec<NaiveDate>)> {
+    let classroom = sqlx::query!(
+        "SELECT school_id, academic_year FROM classroom WHERE id=$1",
+        class_id
+    )
+    .fetch_one(pool)
+    .await?;
+    let (year_start, year_end) = utils::academic_year_period(classroom.academic_year);
+    let from = NaiveDate::parse_from_str(&year_start, "%Y-%m-%d")?;
+    let to = std::cmp::min(today, NaiveDate::parse_from_str(&year_end, "%Y-%m-%d")?);
+    let school_days = calendar::school_days(pool, &classroom.school_id, from, to).await?;
+    Ok((from, school_days))
+}
+
+pub async fn get_class_streaks(pool: &Pool<Sqlite>, class_id: &str) -> Result<ClassStreaks> {
+    let today = Local::now().date_naive();
+    let (from, school_days) = class_school_days(pool, class_id, today).await?;
+    let from = from.to_string();
+
+    Ok(ClassStreaks {
+        points: calc_streak(
+            &school_days,
+            &positive_point_days(pool, class_id, &from).await?,
+            today,
+        ),
+        checklist: calc_streak(
+            &school_days,
+            &full_checklist_days(pool, class_id, &from).await?,
+            today,
+        ),
+        leftovers: calc_streak(
+            &school_days,
+            &under_baseline_days(pool, class_id, &from).await?,
+            today,
+        ),
+    })
+}
+
+pub async fn get_student_streaks(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    student_id: i64,
+) -> Result<StudentStreaks> {
+    let today = Local::now().date_naive();
+    let (from, school_days) = class_school_days(pool, class_id, today).await?;
+    let from = from.to_string();
+
+    Ok(StudentStreaks {
+        checklist: calc_streak(
+            &school_days,
+            &student_checklist_days(pool, class_id, student_id, &from).await?,
+            today,
+        ),
+    })
+}
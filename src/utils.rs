@@ -4,65 +4,243 @@ use anyhow::Result;
 use argon2::password_hash::SaltString;
 use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier};
 use bytes::{Buf, Bytes};
-use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use cookie::{Cookie, SameSite};
+use futures_util::stream;
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Empty, Full};
+use http_body_util::{BodyExt, Empty, Full, LengthLimitError, Limited, StreamBody};
+use hyper::body::Frame;
 use hyper::header::COOKIE;
 use hyper::{header, Request, Response, StatusCode};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Sqlite};
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
+use ulid::Ulid;
 
 use crate::config::CONFIG;
+use crate::error::{ApiError, ApiResult};
+use crate::router::{ClientAddr, PathParams};
 
 pub const CLASS_TOKEN: &str = "class_token";
 pub const STUDENT_TOKEN: &str = "student_token";
+pub const TEACHER_TOKEN: &str = "teacher_token";
+pub const CSRF_TOKEN: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
 
-pub type HandlerResponse = Result<Response<BoxBody<Bytes, hyper::Error>>>;
+// Streamed bodies can fail after the headers have been sent
+pub type BodyError = Box<dyn std::error::Error + Send + Sync>;
 
-pub fn empty() -> BoxBody<Bytes, hyper::Error> {
+pub type HandlerResponse = ApiResult<Response<BoxBody<Bytes, BodyError>>>;
+
+pub fn empty() -> BoxBody<Bytes, BodyError> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
         .boxed()
 }
 
-fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
+pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, BodyError> {
     Full::new(chunk.into())
         .map_err(|never| match never {})
         .boxed()
 }
 
+// Read the whole body up to MAX_BODY_BYTES
+async fn collect_body(req: Request<hyper::body::Incoming>) -> ApiResult<impl Buf> {
+    let body = Limited::new(req.into_body(), CONFIG.max_body_bytes)
+        .collect()
+        .await
+        .map_err(|e| match e.downcast::<LengthLimitError>() {
+            Ok(_) => ApiError::PayloadTooLarge,
+            Err(_) => ApiError::InvalidParams,
+        })?;
+    Ok(body.aggregate())
+}
+
 pub async fn parse_req_json<T: for<'de> serde::de::Deserialize<'de>>(
     req: Request<hyper::body::Incoming>,
-) -> Result<T> {
-    let body = req.collect().await?.aggregate();
+) -> ApiResult<T> {
+    let body = collect_body(req).await?;
     let data = serde_json::from_reader::<_, T>(body.reader())?;
     Ok(data)
 }
 
-pub async fn read_body_req(req: Request<hyper::body::Incoming>) -> Result<String> {
-    let body = req.collect().await?.aggregate();
+pub async fn read_body_req(req: Request<hyper::body::Incoming>) -> ApiResult<String> {
+    let body = collect_body(req).await?;
     let mut body_str = String::new();
-    body.reader().read_to_string(&mut body_str);
+    body.reader()
+        .read_to_string(&mut body_str)
+        .map_err(|_| ApiError::InvalidParams)?;
     Ok(body_str)
 }
 
+pub fn get_query_param(req: &Request<hyper::body::Incoming>, key: &str) -> Option<String> {
+    form_urlencoded::parse(req.uri().query()?.as_bytes())
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+// Address of the client. Behind a reverse proxy (TRUST_PROXY), the first address of X-Forwarded-For.
+pub fn get_client_ip(req: &Request<hyper::body::Incoming>) -> IpAddr {
+    let forwarded = req
+        .headers()
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .and_then(|v| v.trim().parse().ok());
+    match forwarded {
+        Some(ip) if CONFIG.trust_proxy => ip,
+        _ => req
+            .extensions()
+            .get::<ClientAddr>()
+            .map(|addr| addr.0.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+    }
+}
+
+// Parameter of the `{key}` segment in the route
+pub fn get_path_param(req: &Request<hyper::body::Incoming>, key: &str) -> Option<String> {
+    req.extensions()
+        .get::<PathParams>()?
+        .get(key)
+        .map(|v| v.to_string())
+}
+
+// Letters of the codes without the confusing ones like 0/O and 1/I
+const CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const JOIN_CODE_LEN: usize = 8;
+
+// Random code of the letters which are easy to read and type
+pub fn generate_code(len: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..len)
+        .map(|_| CODE_CHARS[rng.gen_range(0..CODE_CHARS.len())] as char)
+        .collect()
+}
+
+// Code which users enter to find the classrooms of the school
+pub fn generate_join_code() -> String {
+    generate_code(JOIN_CODE_LEN)
+}
+
+// Give the codes to the schools without them, such as the ones from before the join codes.
+// Returns the number of the schools.
+pub async fn backfill_join_codes(pool: &Pool<Sqlite>) -> Result<u64> {
+    let school_ids = sqlx::query_scalar!("SELECT id FROM school WHERE join_code IS NULL")
+        .fetch_all(pool)
+        .await?;
+    for school_id in &school_ids {
+        // Retry the rare collisions with the existing codes
+        loop {
+            let join_code = generate_join_code();
+            let result = sqlx::query!(
+                "UPDATE school SET join_code=$1 WHERE id=$2",
+                join_code,
+                school_id
+            )
+            .execute(pool)
+            .await;
+            match result {
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => continue,
+                result => {
+                    result?;
+                    break;
+                }
+            }
+        }
+    }
+    Ok(school_ids.len() as u64)
+}
+
+// Page and items per page from the `page` and `per_page` query
+pub fn get_query_page(req: &Request<hyper::body::Incoming>) -> ApiResult<(i64, i64)> {
+    let parse = |key: &'static str, default: i64, max: i64| match get_query_param(req, key) {
+        Some(v) => v
+            .parse::<i64>()
+            .ok()
+            .filter(|v| (1..=max).contains(v))
+            .ok_or(ApiError::InvalidField(key)),
+        None => Ok(default),
+    };
+    Ok((parse("page", 1, i64::MAX)?, parse("per_page", 50, 200)?))
+}
+
+fn argon2_params() -> Params {
+    Params::new(
+        CONFIG.argon2_memory_kib,
+        CONFIG.argon2_iterations,
+        CONFIG.argon2_parallelism,
+        None,
+    )
+    .expect("Invalid Argon2 params.")
+}
+
+fn argon2_with(params: Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, argon2::Version::V0x13, params)
+}
+
+fn argon2() -> Argon2<'static> {
+    argon2_with(argon2_params())
+}
+
 pub fn compute_password_hash(password: String) -> String {
     let salt = SaltString::generate(&mut rand::thread_rng());
-    Argon2::new(
-        Algorithm::Argon2id,
-        argon2::Version::V0x13,
-        Params::new(15000, 2, 1, None).unwrap(),
+    argon2()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+// Passwords need PASSWORD_MIN_LENGTH or more characters of 2 or more kinds
+// (lowercase, uppercase, digits and others)
+pub fn check_password_strength(password: &str) -> ApiResult<()> {
+    let kinds = [
+        password.chars().any(|c| c.is_ascii_lowercase()),
+        password.chars().any(|c| c.is_ascii_uppercase()),
+        password.chars().any(|c| c.is_ascii_digit()),
+        password.chars().any(|c| !c.is_ascii_alphanumeric()),
+    ];
+    if password.chars().count() < CONFIG.password_min_length
+        || kinds.iter().filter(|k| **k).count() < 2
+    {
+        return Err(ApiError::WeakPassword);
+    }
+    Ok(())
+}
+
+// Change the password of the classroom and sign out all the devices. Returns false if it doesn't exist.
+pub async fn set_class_password(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    password: String,
+) -> Result<bool> {
+    let hash = compute_password_hash(password);
+
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query!(
+        "UPDATE classroom SET password_hash=$1 WHERE id=$2 AND archived_at IS NULL",
+        hash,
+        class_id
     )
-    .hash_password(password.as_bytes(), &salt)
-    .unwrap()
-    .to_string()
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    sqlx::query!("DELETE FROM class_token WHERE class_id=$1", class_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM password_reset WHERE class_id=$1", class_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(true)
 }
 
-pub fn response_json(
-    status: StatusCode,
-    json: String,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+pub fn response_json(status: StatusCode, json: String) -> HandlerResponse {
     let response = Response::builder()
         .status(status)
         .header(header::CONTENT_TYPE, "application/json")
@@ -74,30 +252,69 @@ pub fn response_struct_json<T>(status: StatusCode, value: &T) -> HandlerResponse
 where
     T: ?Sized + Serialize,
 {
-    let json = serde_json::to_string(value)?;
+    let json = serde_json::to_string(value).map_err(|e| ApiError::Internal(e.into()))?;
     response_json(status, json)
 }
 
+// Response which sends the chunks from the channel as they arrive.
+// When the producer fails, the body is aborted so that the clients don't take it as complete.
+pub fn response_stream(
+    status: StatusCode,
+    content_type: &str,
+    rx: Receiver<Bytes>,
+    producer: JoinHandle<Result<()>>,
+) -> HandlerResponse {
+    let chunks = stream::unfold((rx, Some(producer)), |(mut rx, producer)| async move {
+        if let Some(chunk) = rx.recv().await {
+            return Some((Ok(Frame::data(chunk)), (rx, producer)));
+        }
+        let error: BodyError = match producer?.await {
+            Ok(Ok(())) => return None,
+            Ok(Err(e)) => e.into(),
+            Err(e) => e.into(),
+        };
+        Some((Err(error), (rx, None)))
+    });
+    let response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(StreamBody::new(chunks).boxed())?;
+    Ok(response)
+}
+
 pub fn response_empty(status: StatusCode) -> HandlerResponse {
     let response = Response::builder().status(status).body(empty())?;
     Ok(response)
 }
 
-#[derive(Serialize)]
-struct ResponseErrorMessage {
-    error: String,
+// The hashes keep their own params, so the ones made with the older params can be verified too
+pub fn verify_password(password: String, hash: String) -> Result<bool> {
+    verify_password_with(&argon2(), &password, &hash)
 }
 
-pub fn response_error_message(status: StatusCode, msg: String) -> HandlerResponse {
-    let res = ResponseErrorMessage { error: msg };
-    let json = serde_json::to_string(&res)?;
-    response_json(status, json)
+fn verify_password_with(argon2: &Argon2, password: &str, hash: &str) -> Result<bool> {
+    let expected_password_hash = PasswordHash::new(hash)?;
+    let result = argon2.verify_password(password.as_bytes(), &expected_password_hash);
+    Ok(result.is_ok())
 }
 
-pub fn verify_password(password: String, hash: String) -> Result<bool> {
-    let expected_password_hash = PasswordHash::new(hash.as_str())?;
-    let result = Argon2::default().verify_password(password.as_bytes(), &expected_password_hash);
-    Ok(result.is_ok())
+// Whether the hash was made with other params than the current ones
+pub fn needs_rehash(hash: &str) -> bool {
+    needs_rehash_with(hash, &argon2_params())
+}
+
+fn needs_rehash_with(hash: &str, current: &Params) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    let Ok(params) = Params::try_from(&hash) else {
+        return true;
+    };
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(argon2::Version::V0x13.into())
+        || params.m_cost() != current.m_cost()
+        || params.t_cost() != current.t_cost()
+        || params.p_cost() != current.p_cost()
 }
 
 pub fn create_cookie(key: String, value: String) -> String {
@@ -128,44 +345,397 @@ pub fn get_cookie(req: &Request<hyper::body::Incoming>, key: String) -> Option<S
                                 return Some(cookie.value().to_string());
                             }
                         }
-                        Err(e) => println!("{}", e.to_string()),
+                        Err(e) => tracing::debug!(error = %e, "invalid cookie"),
                     }
                 }
             }
 
-            Err(e) => println!("{}", e.to_string()),
+            Err(e) => tracing::debug!(error = %e, "invalid cookie header"),
+        }
+    }
+    None
+}
+
+pub fn get_bearer_token(req: &Request<hyper::body::Incoming>) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+// Token of the class, student or teacher. The bearer token is used if any, then the cookie.
+pub fn get_token(req: &Request<hyper::body::Incoming>, cookie_name: &str) -> Option<String> {
+    match get_bearer_token(req) {
+        Some(token) if !is_admin(req) => Some(token),
+        _ => get_cookie(req, cookie_name.to_string()),
+    }
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct CsrfResponse {
+    pub csrf_token: String,
+}
+
+// Send the new token as the cookie, or in the body for the clients using the bearer token.
+// Cookie clients get a new CSRF token in the body, which they send back in X-CSRF-Token.
+pub fn response_token(cookie_name: &str, token: String, in_body: bool) -> HandlerResponse {
+    if in_body {
+        return response_struct_json(StatusCode::OK, &TokenResponse { token });
+    }
+    let csrf_token = Ulid::new().to_string();
+    let token_cookie = create_cookie(cookie_name.to_string(), token);
+    let csrf_cookie = create_cookie(CSRF_TOKEN.to_string(), csrf_token.clone());
+    let mut response = response_struct_json(StatusCode::OK, &CsrfResponse { csrf_token })?;
+    response.headers_mut().append(
+        header::SET_COOKIE,
+        header::HeaderValue::from_str(&token_cookie)?,
+    );
+    response.headers_mut().append(
+        header::SET_COOKIE,
+        header::HeaderValue::from_str(&csrf_cookie)?,
+    );
+    Ok(response)
+}
+
+// Whether the request has any token cookie of the class, student or teacher
+pub fn has_token_cookie(req: &Request<hyper::body::Incoming>) -> bool {
+    [CLASS_TOKEN, STUDENT_TOKEN, TEACHER_TOKEN]
+        .iter()
+        .any(|name| get_cookie(req, name.to_string()).is_some())
+}
+
+// Compare the secrets in the time which doesn't depend on the contents
+pub fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Delete the tokens of the request so that they can't be used after the logout
+pub async fn revoke_tokens(
+    pool: &Pool<Sqlite>,
+    req: &Request<hyper::body::Incoming>,
+) -> Result<()> {
+    if let Some(token) = get_token(req, CLASS_TOKEN) {
+        sqlx::query!("DELETE FROM class_token WHERE token=$1", token)
+            .execute(pool)
+            .await?;
+    }
+    if let Some(token) = get_token(req, STUDENT_TOKEN) {
+        sqlx::query!("DELETE FROM student_token WHERE token=$1", token)
+            .execute(pool)
+            .await?;
+    }
+    if let Some(token) = get_token(req, TEACHER_TOKEN) {
+        sqlx::query!("DELETE FROM teacher_token WHERE token=$1", token)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+// Administrators send ADMIN_TOKEN as the bearer token
+pub fn is_admin(req: &Request<hyper::body::Incoming>) -> bool {
+    match (&CONFIG.admin_token, get_bearer_token(req)) {
+        (Some(admin_token), Some(token)) => {
+            !admin_token.is_empty() && secure_eq(admin_token.as_bytes(), token.as_bytes())
         }
+        _ => false,
     }
-    return None;
 }
 
 pub async fn get_class_id_from_token(
     pool: &Pool<Sqlite>,
     req: &Request<hyper::body::Incoming>,
-) -> Result<String, HandlerResponse> {
-    let token = match get_cookie(req, CLASS_TOKEN.to_string()) {
+) -> ApiResult<String> {
+    let token = match get_token(req, CLASS_TOKEN) {
         Some(token) => token,
-        None => return Err(response_empty(StatusCode::UNAUTHORIZED)),
+        None => return Err(ApiError::Unauthorized),
     };
-    let result = sqlx::query_scalar!("SELECT class_id FROM class_token WHERE token=$1", token)
+    let class_id = sqlx::query_scalar!("SELECT class_id FROM class_token WHERE token=$1", token)
         .fetch_optional(pool)
-        .await;
-    let class_id = match result {
-        Ok(v) => match v {
-            Some(class_id) => class_id,
-            None => {
-                return Err(response_error_message(
-                    StatusCode::UNAUTHORIZED,
-                    "Invalid token".to_string(),
-                ))
+        .await?;
+    class_id.ok_or(ApiError::InvalidToken)
+}
+
+// Archive the classroom keeping the history. Returns false if it doesn't exist.
+pub async fn archive_classroom(pool: &Pool<Sqlite>, class_id: &str) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let archived = archive_classroom_in(&mut tx, class_id).await?;
+    tx.commit().await?;
+    Ok(archived)
+}
+
+async fn archive_classroom_in(conn: &mut SqliteConnection, class_id: &str) -> Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE classroom SET archived_at=datetime('now', 'localtime') WHERE id=$1 AND archived_at IS NULL",
+        class_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    // Sign out all sessions of the classroom
+    sqlx::query!(
+        "DELETE FROM teacher_token WHERE teacher_id IN (SELECT id FROM teacher WHERE class_id=$1)",
+        class_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!("DELETE FROM class_token WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM password_reset WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM student_token WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+
+    // Teachers left on the classroom can sign up again with the email for another classroom
+    sqlx::query!("UPDATE teacher SET email=NULL WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(true)
+}
+
+// Delete the classroom with the data of it. Returns false if it doesn't exist.
+pub async fn delete_classroom(pool: &Pool<Sqlite>, class_id: &str) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let deleted = delete_classroom_in(&mut tx, class_id).await?;
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+async fn delete_classroom_in(conn: &mut SqliteConnection, class_id: &str) -> Result<bool> {
+    let result = sqlx::query!("DELETE FROM classroom WHERE id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        "DELETE FROM teacher_token WHERE teacher_id IN (SELECT id FROM teacher WHERE class_id=$1)",
+        class_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!("DELETE FROM teacher WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM class_token WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM password_reset WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM student_token WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM day_status WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM checklist WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM leftover_item WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM day_energy WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM latest_sensor_time WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM correction_log WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM challenge_class WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!("DELETE FROM achievement WHERE class_id=$1", class_id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(true)
+}
+
+pub struct RolloverOptions {
+    pub year: i64, // New academic year
+    pub carry_names: bool,
+    pub promote: bool,
+    pub carry_devices: bool,
+}
+
+#[derive(Serialize)]
+pub struct RolledClassroom {
+    pub from_id: String,
+    pub id: String,
+    pub grade: i64,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct RolloverResult {
+    pub archived: Vec<String>,
+    pub created: Vec<RolledClassroom>,
+}
+
+// Archive the classrooms of the previous academic year and create the classrooms of the new year.
+// Carried classrooms keep the password and the teachers, and the devices signed in to the old classroom with carry_devices.
+pub async fn rollover_school(
+    pool: &Pool<Sqlite>,
+    school_id: &str,
+    options: &RolloverOptions,
+) -> Result<RolloverResult> {
+    let mut tx = pool.begin().await?;
+    let prev_year = options.year - 1;
+    let classrooms = sqlx::query!(
+        "SELECT id, grade, name, password_hash FROM classroom
+        WHERE school_id=$1 AND academic_year=$2 AND archived_at IS NULL",
+        school_id,
+        prev_year
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut result = RolloverResult {
+        archived: Vec::new(),
+        created: Vec::new(),
+    };
+
+    for classroom in classrooms {
+        let grade = if options.promote {
+            classroom.grade + 1
+        } else {
+            classroom.grade
+        };
+
+        // Graduated classrooms are not carried
+        if (options.carry_names || options.promote) && grade <= CONFIG.max_grade {
+            let id = Ulid::new().to_string();
+            sqlx::query!(
+                "INSERT INTO classroom(id, school_id, grade, name, password_hash, academic_year) VALUES($1, $2, $3, $4, $5, $6)",
+                id,
+                school_id,
+                grade,
+                classroom.name,
+                classroom.password_hash,
+                options.year
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            // Teachers move with the classroom. They stay signed in.
+            sqlx::query!(
+                "UPDATE teacher SET class_id=$1 WHERE class_id=$2",
+                id,
+                classroom.id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            if options.carry_devices {
+                sqlx::query!(
+                    "UPDATE class_token SET class_id=$1 WHERE class_id=$2",
+                    id,
+                    classroom.id
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!(
+                    "UPDATE latest_sensor_time SET class_id=$1 WHERE class_id=$2",
+                    id,
+                    classroom.id
+                )
+                .execute(&mut *tx)
+                .await?;
             }
-        },
-        Err(e) => {
-            println!("{}", e.to_string());
-            return Err(response_empty(StatusCode::INTERNAL_SERVER_ERROR));
+
+            result.created.push(RolledClassroom {
+                from_id: classroom.id.clone(),
+                id,
+                grade,
+                name: classroom.name,
+            });
         }
-    };
-    return Ok(class_id);
+
+        archive_classroom_in(&mut tx, &classroom.id).await?;
+        result.archived.push(classroom.id);
+    }
+
+    tx.commit().await?;
+    Ok(result)
+}
+
+// Archive the school and the classrooms of it. Returns false if it doesn't exist.
+pub async fn archive_school(pool: &Pool<Sqlite>, school_id: &str) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query!(
+        "UPDATE school SET archived_at=datetime('now', 'localtime') WHERE id=$1 AND archived_at IS NULL",
+        school_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let class_ids = sqlx::query_scalar!(
+        "SELECT id FROM classroom WHERE school_id=$1 AND archived_at IS NULL",
+        school_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for class_id in class_ids {
+        archive_classroom_in(&mut tx, &class_id).await?;
+    }
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+// Delete the school with all the data of it. Returns false if it doesn't exist.
+pub async fn delete_school(pool: &Pool<Sqlite>, school_id: &str) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query!("DELETE FROM school WHERE id=$1", school_id)
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let class_ids = sqlx::query_scalar!("SELECT id FROM classroom WHERE school_id=$1", school_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    for class_id in class_ids {
+        delete_classroom_in(&mut tx, &class_id).await?;
+    }
+    sqlx::query!("DELETE FROM lunch_menu WHERE school_id=$1", school_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM school_calendar WHERE school_id=$1", school_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "DELETE FROM challenge_class WHERE challenge_id IN (SELECT id FROM challenge WHERE school_id=$1)",
+        school_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM challenge WHERE school_id=$1", school_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(true)
 }
 
 pub struct StudentInfo {
@@ -176,34 +746,45 @@ pub struct StudentInfo {
 pub async fn get_student_info_from_token(
     pool: &Pool<Sqlite>,
     req: &Request<hyper::body::Incoming>,
-) -> Result<StudentInfo, HandlerResponse> {
-    let token = match get_cookie(req, STUDENT_TOKEN.to_string()) {
+) -> ApiResult<StudentInfo> {
+    let token = match get_token(req, STUDENT_TOKEN) {
         Some(token) => token,
-        None => return Err(response_empty(StatusCode::UNAUTHORIZED)),
+        None => return Err(ApiError::Unauthorized),
     };
-    let result = sqlx::query_as!(
+    let info = sqlx::query_as!(
         StudentInfo,
         "SELECT class_id, student_id FROM student_token WHERE token=$1",
         token
     )
     .fetch_optional(pool)
-    .await;
-    let info = match result {
-        Ok(v) => match v {
-            Some(info) => info,
-            None => {
-                return Err(response_error_message(
-                    StatusCode::UNAUTHORIZED,
-                    "Invalid token".to_string(),
-                ))
-            }
-        },
-        Err(e) => {
-            println!("{}", e.to_string());
-            return Err(response_empty(StatusCode::INTERNAL_SERVER_ERROR));
-        }
+    .await?;
+    info.ok_or(ApiError::InvalidToken)
+}
+
+pub struct TeacherInfo {
+    pub teacher_id: String,
+    pub class_id: String,
+}
+
+pub async fn get_teacher_info_from_token(
+    pool: &Pool<Sqlite>,
+    req: &Request<hyper::body::Incoming>,
+) -> ApiResult<TeacherInfo> {
+    let token = match get_token(req, TEACHER_TOKEN) {
+        Some(token) => token,
+        None => return Err(ApiError::Unauthorized),
     };
-    return Ok(info);
+    let info = sqlx::query_as!(
+        TeacherInfo,
+        r#"SELECT teacher.id AS "teacher_id!", teacher.class_id AS "class_id!" FROM teacher_token
+        JOIN teacher ON teacher.id = teacher_token.teacher_id
+        JOIN classroom ON classroom.id = teacher.class_id
+        WHERE token=$1 AND classroom.archived_at IS NULL"#,
+        token
+    )
+    .fetch_optional(pool)
+    .await?;
+    info.ok_or(ApiError::InvalidToken)
 }
 
 pub fn parse_str_time(str_time: &str) -> Result<DateTime<Utc>> {
@@ -218,14 +799,28 @@ pub struct Sensor {
     #[serde(alias = "isPeople")]
     is_people: bool,
     lux: f64,
+    #[allow(dead_code)]
     useairconditioner: bool,
+    #[allow(dead_code)]
     airconditioner_time: String,
 }
 
+// Factors to estimate the saving
+pub const AIRCONDITIONER_KW: f64 = 1.5; // Power consumption of an air conditioner
+pub const LIGHTING_KW: f64 = 5.4; // Power consumption of the lights of a classroom
+pub const CO2_KG_PER_KWH: f64 = 0.378; // CO2 emission factor of electricity
+pub const CO2_KG_PER_FOOD_KG: f64 = 2.501; // CO2 emission factor of food waste
+
+fn calc_discomfort_index(sensor: &Sensor) -> f64 {
+    0.81 * sensor.temperature + 0.01 * sensor.humidity * (0.99 * sensor.temperature - 14.3) + 46.3
+}
+
+fn duration_hours(duraton_msec: i64) -> f64 {
+    i64::clamp(duraton_msec, 0, CONFIG.sensor_interval as i64) as f64 / (1000.0 * 60.0 * 60.0)
+}
+
 pub fn calc_airconditionaer_point(sensor: &Sensor, duraton_msec: i64) -> i64 {
-    let discomfort_index = 0.81 * sensor.temperature
-        + 0.01 * sensor.humidity * (0.99 * sensor.temperature - 14.3)
-        + 46.3;
+    let discomfort_index = calc_discomfort_index(sensor);
 
     // Check satisfy air conditioner usage standards
     // let satisfy_airconditionaer = if sensor.is_people == false {
@@ -250,27 +845,43 @@ pub fn calc_airconditionaer_point(sensor: &Sensor, duraton_msec: i64) -> i64 {
     //     return 0.0;
     // }
 
-    let co2p = 1.5 * 0.378;
+    let co2p = AIRCONDITIONER_KW * CO2_KG_PER_KWH;
     let n = i64::clamp(duraton_msec, 0, CONFIG.sensor_interval as i64) as f64 / (1000.0 * 60.0);
-    println!(
-        "read d {}",
-        i64::clamp(duraton_msec, 0, CONFIG.sensor_interval as i64) as f64
+    tracing::trace!(
+        co2p,
+        n,
+        duration_msec = duraton_msec,
+        "airconditioner point"
     );
-    println!("co2p:{} n:{} duration:{}", co2p, n, duraton_msec);
     let point = co2p * (10.0 - (discomfort_index - 69.5).abs()) * n;
     if point > 0.5 {
         point.ceil() as i64
     } else {
-        return 0;
+        0
     }
 }
 
 pub fn calc_lux_point(sensor: &Sensor, duraton_msec: i64) -> i64 {
     let n = i64::clamp(duraton_msec, 0, CONFIG.sensor_interval as i64) as f64 / (1000.0 * 60.0);
-    if sensor.is_people == false && sensor.lux < 30.0 {
-        return (5.4 * 0.378 * 2.0 * n) as i64;
+    if !sensor.is_people && sensor.lux < 30.0 {
+        (LIGHTING_KW * CO2_KG_PER_KWH * 2.0 * n) as i64
+    } else {
+        0
+    }
+}
+
+// Estimated electricity saved by keeping the room comfortable (kWh)
+pub fn calc_airconditioner_saving(sensor: &Sensor, duraton_msec: i64) -> f64 {
+    let comfort = (10.0 - (calc_discomfort_index(sensor) - 69.5).abs()) / 10.0;
+    AIRCONDITIONER_KW * f64::clamp(comfort, 0.0, 1.0) * duration_hours(duraton_msec)
+}
+
+// Estimated electricity saved by turning off the lights of an empty room (kWh)
+pub fn calc_lighting_saving(sensor: &Sensor, duraton_msec: i64) -> f64 {
+    if !sensor.is_people && sensor.lux < 30.0 {
+        LIGHTING_KW * duration_hours(duraton_msec)
     } else {
-        return 0;
+        0.0
     }
 }
 
@@ -281,23 +892,477 @@ pub struct DayStatus {
     pub attend: Option<i64>,
     pub leftovers: Option<i64>,
     pub date: String,
+    pub servings: Option<i64>,
 }
 
-pub fn calc_leftovers_point(prev_daystatus: &DayStatus, daystatus: &DayStatus) -> i64 {
-    let prev_point = if prev_daystatus.attend.is_none() || prev_daystatus.leftovers.is_none() {
-        0
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LeftoverUnit {
+    G,
+    Kg,
+    #[serde(alias = "portions")]
+    Portion,
+}
+
+impl LeftoverUnit {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LeftoverUnit::G => "g",
+            LeftoverUnit::Kg => "kg",
+            LeftoverUnit::Portion => "portion",
+        }
+    }
+
+    pub fn to_grams(self, amount: f64) -> f64 {
+        match self {
+            LeftoverUnit::G => amount,
+            LeftoverUnit::Kg => amount * 1000.0,
+            LeftoverUnit::Portion => amount * CONFIG.portion_grams,
+        }
+    }
+}
+
+// Expected leftovers per serving (1030g for 30 servings)
+pub const LEFTOVERS_GRAMS_PER_SERVING: f64 = 1030.0 / 30.0;
+
+// Number of past records needed to use the baseline of a dish
+const MIN_BASELINE_SAMPLES: i64 = 3;
+
+pub fn today() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+pub fn academic_year(date: NaiveDate) -> i64 {
+    if date.month() < CONFIG.academic_year_start_month {
+        date.year() as i64 - 1
     } else {
-        (((1030.0 * prev_daystatus.attend.unwrap() as f64) / 30.0
-            - prev_daystatus.leftovers.unwrap() as f64)
-            * 2.501
-            * (1.0 / 10.0)) as i64
-    };
+        date.year() as i64
+    }
+}
+
+pub fn current_academic_year() -> i64 {
+    academic_year(Local::now().date_naive())
+}
 
-    let current_point = (((1030.0 * daystatus.attend.unwrap() as f64) / 30.0
-        - daystatus.leftovers.unwrap() as f64)
-        * 2.501
-        * (1.0 / 10.0)) as i64;
+// First and last date of the academic year
+pub fn academic_year_period(year: i64) -> (String, String) {
+    let start_month = CONFIG.academic_year_start_month;
+    let start = NaiveDate::from_ymd_opt(year as i32, start_month, 1).unwrap();
+    let end = NaiveDate::from_ymd_opt(year as i32 + 1, start_month, 1).unwrap()
+        - chrono::Duration::days(1);
+    (start.to_string(), end.to_string())
+}
+
+#[derive(Serialize)]
+pub struct DishBaseline {
+    pub dish: String,
+    pub grams_per_serving: f64,
+    pub samples: i64,
+}
 
-    println!("left c:{} prev:{}", current_point, prev_point);
+// Average leftovers per serving of each dish in the school before the date
+pub async fn get_dish_baselines(
+    pool: &Pool<Sqlite>,
+    school_id: &str,
+    date: &str,
+) -> Result<Vec<DishBaseline>> {
+    let baselines = sqlx::query_as!(
+        DishBaseline,
+        r#"SELECT dish AS "dish!", SUM(grams) / SUM(servings) AS "grams_per_serving!: f64", COUNT(*) AS "samples!: i64"
+        FROM leftover_item
+        JOIN classroom ON classroom.id = leftover_item.class_id
+        WHERE classroom.school_id=$1 AND leftover_item.date < $2 AND servings > 0
+        GROUP BY dish"#,
+        school_id,
+        date
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(baselines)
+}
+
+// Expected leftovers per serving of the dishes, from the sums of the history (grams, servings, samples)
+fn menu_baseline(menu: &[String], history: &HashMap<String, (f64, i64, i64)>) -> f64 {
+    // Dishes without enough history share the flat baseline
+    let default_baseline = LEFTOVERS_GRAMS_PER_SERVING / menu.len() as f64;
+    menu.iter()
+        .map(|dish| match history.get(dish) {
+            Some((grams, servings, samples)) if *samples >= MIN_BASELINE_SAMPLES => {
+                grams / *servings as f64
+            }
+            _ => default_baseline,
+        })
+        .sum()
+}
+
+// Baselines of the school on the days in the period. The history is loaded at once,
+// so that the callers don't aggregate it day by day.
+pub struct LeftoversBaselines {
+    baselines: HashMap<String, f64>, // date
+}
+
+impl LeftoversBaselines {
+    pub async fn load(pool: &Pool<Sqlite>, school_id: &str, from: &str, to: &str) -> Result<Self> {
+        let mut menus: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for row in sqlx::query!(
+            "SELECT date, dish FROM lunch_menu WHERE school_id=$1 AND date BETWEEN $2 AND $3",
+            school_id,
+            from,
+            to
+        )
+        .fetch_all(pool)
+        .await?
+        {
+            menus.entry(row.date).or_default().push(row.dish);
+        }
+
+        let mut baselines = HashMap::new();
+        if menus.is_empty() {
+            return Ok(Self { baselines });
+        }
+
+        let items = sqlx::query!(
+            r#"SELECT leftover_item.date, dish, grams, servings AS "servings!"
+            FROM leftover_item
+            JOIN classroom ON classroom.id = leftover_item.class_id
+            WHERE classroom.school_id=$1 AND leftover_item.date < $2 AND servings > 0
+            ORDER BY leftover_item.date"#,
+            school_id,
+            to
+        )
+        .fetch_all(pool)
+        .await?;
+
+        // Add up the items before each menu day
+        let mut history: HashMap<String, (f64, i64, i64)> = HashMap::new();
+        let mut items = items.into_iter().peekable();
+        for (date, menu) in menus {
+            while let Some(item) = items.next_if(|item| item.date < date) {
+                let sums = history.entry(item.dish).or_default();
+                sums.0 += item.grams;
+                sums.1 += item.servings;
+                sums.2 += 1;
+            }
+            let baseline = menu_baseline(&menu, &history);
+            baselines.insert(date, baseline);
+        }
+        Ok(Self { baselines })
+    }
+
+    // Days without the menu use the flat baseline
+    pub fn get(&self, date: &str) -> f64 {
+        self.baselines
+            .get(date)
+            .copied()
+            .unwrap_or(LEFTOVERS_GRAMS_PER_SERVING)
+    }
+}
+
+// Expected leftovers per serving of the class on the date, computed from the menu
+pub async fn get_leftovers_baseline(
+    pool: &Pool<Sqlite>,
+    class_id: &str,
+    date: &str,
+) -> Result<f64> {
+    let school_id = sqlx::query_scalar!("SELECT school_id FROM classroom WHERE id=$1", class_id)
+        .fetch_one(pool)
+        .await?;
+    let baselines = LeftoversBaselines::load(pool, &school_id, date, date).await?;
+    Ok(baselines.get(date))
+}
+
+fn leftovers_point(daystatus: &DayStatus, baseline: f64) -> i64 {
+    // Use attendance when the serving count is not registered
+    let servings = daystatus.servings.or(daystatus.attend);
+    match (servings, daystatus.leftovers) {
+        (Some(servings), Some(leftovers)) => {
+            ((baseline * servings as f64 - leftovers as f64) * CO2_KG_PER_FOOD_KG * (1.0 / 10.0))
+                as i64
+        }
+        _ => 0,
+    }
+}
+
+// baseline: expected leftovers per serving (gram)
+pub fn calc_leftovers_point(
+    prev_daystatus: &DayStatus,
+    daystatus: &DayStatus,
+    baseline: f64,
+) -> i64 {
+    let prev_point = leftovers_point(prev_daystatus, baseline);
+    let current_point = leftovers_point(daystatus, baseline);
+
+    tracing::trace!(current_point, prev_point, "leftovers point");
     current_point - prev_point
 }
+
+#[derive(Serialize)]
+pub struct ImpactFactors {
+    pub airconditioner_kw: f64,
+    pub lighting_kw: f64,
+    pub co2_kg_per_kwh: f64,
+    pub co2_kg_per_food_kg: f64,
+    pub leftovers_grams_per_serving: f64,
+}
+
+#[derive(Serialize)]
+pub struct Impact {
+    pub from: String,
+    pub to: String,
+    pub airconditioner_kwh: f64,
+    pub lighting_kwh: f64,
+    pub energy_kwh: f64,
+    pub food_waste_kg: f64, // Reduced food waste from the baseline
+    pub co2_kg: f64,
+    pub factors: ImpactFactors,
+}
+
+// Estimated saving of the classes of the school in the period (from and to are inclusive)
+pub async fn calc_impact(
+    pool: &Pool<Sqlite>,
+    school_id: &str,
+    class_ids: &[String],
+    from: &str,
+    to: &str,
+) -> Result<Impact> {
+    let mut airconditioner_kwh = 0.0;
+    let mut lighting_kwh = 0.0;
+    let mut food_waste_kg = 0.0;
+    let baselines = LeftoversBaselines::load(pool, school_id, from, to).await?;
+
+    for class_id in class_ids {
+        let energy = sqlx::query!(
+            r#"SELECT COALESCE(SUM(airconditioner_kwh), 0.0) AS "airconditioner_kwh!: f64", COALESCE(SUM(lighting_kwh), 0.0) AS "lighting_kwh!: f64"
+            FROM day_energy WHERE class_id=$1 AND date BETWEEN $2 AND $3"#,
+            class_id,
+            from,
+            to
+        )
+        .fetch_one(pool)
+        .await?;
+        airconditioner_kwh += energy.airconditioner_kwh;
+        lighting_kwh += energy.lighting_kwh;
+
+        let day_status_list = sqlx::query_as!(
+            DayStatus,
+            "SELECT * FROM day_status WHERE class_id=$1 AND date BETWEEN $2 AND $3",
+            class_id,
+            from,
+            to
+        )
+        .fetch_all(pool)
+        .await?;
+
+        for status in day_status_list {
+            let servings = status.servings.or(status.attend);
+            if let (Some(servings), Some(leftovers)) = (servings, status.leftovers) {
+                let baseline = baselines.get(&status.date);
+                food_waste_kg += (baseline * servings as f64 - leftovers as f64) / 1000.0;
+            }
+        }
+    }
+
+    let energy_kwh = airconditioner_kwh + lighting_kwh;
+    Ok(Impact {
+        from: from.to_string(),
+        to: to.to_string(),
+        airconditioner_kwh,
+        lighting_kwh,
+        energy_kwh,
+        food_waste_kg,
+        co2_kg: energy_kwh * CO2_KG_PER_KWH + food_waste_kg * CO2_KG_PER_FOOD_KG,
+        factors: ImpactFactors {
+            airconditioner_kw: AIRCONDITIONER_KW,
+            lighting_kw: LIGHTING_KW,
+            co2_kg_per_kwh: CO2_KG_PER_KWH,
+            co2_kg_per_food_kg: CO2_KG_PER_FOOD_KG,
+            leftovers_grams_per_serving: LEFTOVERS_GRAMS_PER_SERVING,
+        },
+    })
+}
+
+// Period from the `from` and `to` query. Defaults to the last 30 days.
+pub fn get_query_period(req: &Request<hyper::body::Incoming>) -> Option<(String, String)> {
+    let today = Local::now().date_naive();
+    let parse = |key: &str, default: NaiveDate| match get_query_param(req, key) {
+        Some(date) => NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok(),
+        None => Some(default),
+    };
+    let to = parse("to", today)?;
+    let from = parse("from", to - chrono::Duration::days(29))?;
+    if from > to {
+        return None;
+    }
+    Some((from.to_string(), to.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_pool;
+
+    async fn count(pool: &Pool<Sqlite>, table: &str, class_id: &str) -> i64 {
+        let column = if table == "classroom" {
+            "id"
+        } else {
+            "class_id"
+        };
+        sqlx::query_scalar(&format!(
+            "SELECT COUNT(*) FROM {} WHERE {}=$1",
+            table, column
+        ))
+        .bind(class_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    // The tests don't read .env, so they use the default params of Config as the current ones
+    fn current_params() -> Params {
+        Params::new(15000, 2, 1, None).unwrap()
+    }
+
+    fn hash_with(params: Params, password: &str) -> String {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        argon2_with(params)
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn old_params_hash_verifies() {
+        let hash = hash_with(Params::new(4096, 1, 1, None).unwrap(), "Passw0rd");
+        let current = argon2_with(current_params());
+        assert!(verify_password_with(&current, "Passw0rd", &hash).unwrap());
+        assert!(!verify_password_with(&current, "passw0rd", &hash).unwrap());
+    }
+
+    #[test]
+    fn old_params_hash_needs_rehash() {
+        let hash = hash_with(Params::new(4096, 1, 1, None).unwrap(), "Passw0rd");
+        assert!(needs_rehash_with(&hash, &current_params()));
+    }
+
+    #[test]
+    fn current_params_hash_does_not_need_rehash() {
+        let hash = hash_with(current_params(), "Passw0rd");
+        assert!(!needs_rehash_with(&hash, &current_params()));
+    }
+
+    #[tokio::test]
+    async fn delete_classroom_removes_rows() {
+        let pool = test_pool().await;
+        for sql in [
+            "INSERT INTO school(id, name) VALUES('s1', 'school')",
+            "INSERT INTO classroom(id, school_id, grade, name, password_hash, academic_year) VALUES('c1', 's1', 1, 'a', '', 2024)",
+            "INSERT INTO classroom(id, school_id, grade, name, password_hash, academic_year) VALUES('c2', 's1', 1, 'b', '', 2024)",
+            "INSERT INTO day_status(class_id, point, date) VALUES('c1', 10, '2024-05-01')",
+            "INSERT INTO day_status(class_id, point, date) VALUES('c2', 10, '2024-05-01')",
+            "INSERT INTO class_token VALUES('t1', 'c1')",
+            "INSERT INTO teacher VALUES('te1', 'c1', 'a@example.com', '')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+
+        assert!(delete_classroom(&pool, "c1").await.unwrap());
+
+        for table in ["classroom", "day_status", "class_token", "teacher"] {
+            assert_eq!(count(&pool, table, "c1").await, 0, "{}", table);
+        }
+        // Other classrooms are kept
+        assert_eq!(count(&pool, "classroom", "c2").await, 1);
+        assert_eq!(count(&pool, "day_status", "c2").await, 1);
+
+        assert!(!delete_classroom(&pool, "c1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn leftovers_baselines_use_history_before_each_day() {
+        let pool = test_pool().await;
+        let mut sqls = vec![
+            "INSERT INTO school(id, name) VALUES('s1', 'school')".to_string(),
+            "INSERT INTO classroom(id, school_id, grade, name, password_hash, academic_year) VALUES('c1', 's1', 1, 'a', '', 2024)".to_string(),
+            "INSERT INTO lunch_menu(school_id, date, dish) VALUES('s1', '2024-05-01', 'rice')".to_string(),
+            "INSERT INTO lunch_menu(school_id, date, dish) VALUES('s1', '2024-05-01', 'soup')".to_string(),
+            "INSERT INTO lunch_menu(school_id, date, dish) VALUES('s1', '2024-05-02', 'rice')".to_string(),
+        ];
+        for (i, (date, grams)) in [("2024-04-30", 30.0), ("2024-05-01", 90.0)]
+            .iter()
+            .flat_map(|item| [*item; MIN_BASELINE_SAMPLES as usize])
+            .enumerate()
+        {
+            sqls.push(format!(
+                "INSERT INTO leftover_item(id, class_id, date, dish, amount, unit, grams, servings) VALUES('i{}', 'c1', '{}', 'rice', {}, 'g', {}, 10)",
+                i, date, grams, grams
+            ));
+        }
+        for sql in sqls {
+            sqlx::query(&sql).execute(&pool).await.unwrap();
+        }
+
+        let baselines = LeftoversBaselines::load(&pool, "s1", "2024-05-01", "2024-05-03")
+            .await
+            .unwrap();
+        // The items of the day itself are not in the history, and soup has no history
+        assert_eq!(
+            baselines.get("2024-05-01"),
+            3.0 + LEFTOVERS_GRAMS_PER_SERVING / 2.0
+        );
+        assert_eq!(baselines.get("2024-05-02"), 6.0);
+        assert_eq!(baselines.get("2024-05-03"), LEFTOVERS_GRAMS_PER_SERVING);
+
+        for date in ["2024-05-01", "2024-05-02", "2024-05-03"] {
+            assert_eq!(
+                get_leftovers_baseline(&pool, "c1", date).await.unwrap(),
+                baselines.get(date)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn response_stream_aborts_on_producer_error() {
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let producer = tokio::task::spawn(async move {
+            tx.send(Bytes::from("row\n")).await?;
+            anyhow::bail!("failed")
+        });
+        let response = response_stream(StatusCode::OK, "text/csv", rx, producer).unwrap();
+        assert!(response.into_body().collect().await.is_err());
+
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let producer = tokio::task::spawn(async move {
+            tx.send(Bytes::from("row\n")).await?;
+            Ok(())
+        });
+        let response = response_stream(StatusCode::OK, "text/csv", rx, producer).unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "row\n");
+    }
+
+    #[tokio::test]
+    async fn backfilled_join_codes_have_app_format() {
+        let pool = test_pool().await;
+        for sql in [
+            "INSERT INTO school(id, name) VALUES('s1', 'a')",
+            "INSERT INTO school(id, name) VALUES('s2', 'b')",
+            "INSERT INTO school(id, name, join_code) VALUES('s3', 'c', 'ABCDEFGH')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+
+        assert_eq!(backfill_join_codes(&pool).await.unwrap(), 2);
+
+        let codes: Vec<(String, String)> =
+            sqlx::query_as("SELECT id, join_code FROM school ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        for (_, code) in &codes {
+            assert_eq!(code.len(), JOIN_CODE_LEN);
+            assert!(code.bytes().all(|c| CODE_CHARS.contains(&c)), "{}", code);
+        }
+        assert_eq!(codes[2], ("s3".to_string(), "ABCDEFGH".to_string()));
+        assert_eq!(backfill_join_codes(&pool).await.unwrap(), 0);
+    }
+}